@@ -1,622 +1,1788 @@
+use crate::env::Env;
+use crate::formation::Formation;
+use crate::output::{OutputSink, TerminalSink};
+use crate::process::Process;
+use crate::procfile::{Procfile, ProcfileError};
+use crate::ready::{self, Probe};
+use crate::restart::{Backoff, Restarts};
+use regex::Regex;
+use signal_hook::SigId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// The signals that the engine cares about.
+const HANDLED_SIGNALS: [libc::c_int; 6] = [
+    libc::SIGTERM,
+    libc::SIGINT,
+    libc::SIGHUP,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+];
+
+// How often readiness probes other than log ones are checked.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub struct EngineOptions {
+    pub formation: Formation,
+    pub port: Option<u32>,
+    pub root: Option<String>,
+    // Seconds to wait for children to exit after SIGTERM before sending SIGKILL.
+    pub timeout: u64,
+    // Refuse to start when a command uses a variable that is not defined.
+    pub strict_env: bool,
+    // Which processes are restarted when they exit, and how soon.
+    pub restart: Restarts,
+    pub backoff: Backoff,
+}
+
+impl Default for EngineOptions {
+    fn default() -> EngineOptions {
+        EngineOptions {
+            formation: Formation::default(),
+            port: None,
+            root: None,
+            timeout: 5,
+            strict_env: false,
+            restart: Restarts::default(),
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Running {
+    child: Child,
+    process: usize,
+    instance: usize,
+    // The last signal the engine sent to this child, if any.
+    signalled: Option<libc::c_int>,
+    // The master side of the child's terminal, when it runs on one.
+    pty: Option<OwnedFd>,
+    started: Instant,
+    // How many times in a row this instance was restarted.
+    restarts: u32,
+    // Raised by the readiness probe, probe threads stop once it is dropped.
+    ready: Arc<AtomicBool>,
+}
+
+// An instance waiting out its backoff delay.
+#[derive(Debug)]
+struct Restart {
+    process: usize,
+    instance: usize,
+    restart: u32,
+    at: Instant,
+    // How the previous run ended
+    status: ExitStatus,
+}
+
 #[derive(Debug)]
 pub struct Engine {
-    //
+    options: EngineOptions,
+    env: HashMap<String, String>,
+    processes: Vec<(String, Process)>,
+    running: BTreeMap<u32, Running>,
+    shutdown: Arc<AtomicBool>,
+    signals: Vec<(libc::c_int, Arc<AtomicBool>)>,
+    signal_ids: Vec<SigId>,
+    selfpipe: Option<UnixStream>,
+    exit_statuses: Vec<(String, ExitStatus)>,
+    exitstatus: Option<ExitStatus>,
+    output: Arc<Mutex<Box<dyn OutputSink>>>,
+    readers: Vec<JoinHandle<()>>,
+    // Process groups of children that have exited and still hold processes
+    // those children left behind.
+    groups: Vec<(u32, String)>,
+    restarts: Vec<Restart>,
+    // Processes in start order that wait for their dependencies to be ready
+    pending: Vec<usize>,
+    // Instances, by process and instance number, that have been ready
+    ready: HashSet<(usize, usize)>,
+}
+
+impl Engine {
+    pub fn new(options: EngineOptions) -> Engine {
+        Engine::with_sink(options, Box::new(TerminalSink::new()))
+    }
+
+    pub fn with_sink(options: EngineOptions, sink: Box<dyn OutputSink>) -> Engine {
+        Engine {
+            options,
+            env: HashMap::new(),
+            processes: Vec::new(),
+            running: BTreeMap::new(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            signals: HANDLED_SIGNALS
+                .iter()
+                .map(|signal| (*signal, Arc::new(AtomicBool::new(false))))
+                .collect(),
+            signal_ids: Vec::new(),
+            selfpipe: None,
+            exit_statuses: Vec::new(),
+            exitstatus: None,
+            output: Arc::new(Mutex::new(sink)),
+            readers: Vec::new(),
+            groups: Vec::new(),
+            restarts: Vec::new(),
+            pending: Vec::new(),
+            ready: HashSet::new(),
+        }
+    }
+
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub fn options(&self) -> &EngineOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut EngineOptions {
+        &mut self.options
+    }
+
+    // Setting the returned flag asks a running engine to shut down.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    // A relative cwd is taken from the root when the process spawns, so a
+    // later change of root still applies. Without a cwd, a command given by
+    // absolute path runs in its own directory as in Foreman, any other in
+    // the root. Procfile entries always run in the root unless annotated.
+    pub fn register(&mut self, name: &str, command: &str, cwd: Option<String>) {
+        let cwd = cwd.or_else(|| command_dir(command));
+        self.register_process(name, command, cwd);
+    }
+
+    fn register_process(&mut self, name: &str, command: &str, cwd: Option<String>) {
+        let process = Process::new(command.to_string(), cwd, None, None);
+        self.processes.push((name.to_string(), process));
+    }
+
+    pub fn clear(&mut self) {
+        self.processes.clear();
+    }
+
+    pub fn load_procfile(&mut self, filename: &str) -> Result<&mut Engine, ProcfileError> {
+        if self.options.root.is_none() {
+            self.options.root = Some(dirname(filename));
+        }
+        let procfile = Procfile::new(Some(filename))?;
+        for entry in procfile.iter() {
+            let cwd = entry.option("cwd").map(String::from);
+            self.register_process(entry.name(), entry.command(), cwd);
+            let process = &mut self.processes.last_mut().unwrap().1;
+            if let Some(Ok(stderr)) = entry.option("stderr").map(str::parse) {
+                process.set_stderr(stderr);
+            }
+            process.set_pty(entry.option("pty") == Some("true"));
+            if let Some(Ok(policy)) = entry.option("restart").map(str::parse) {
+                self.options.restart.set_unless_named(entry.name(), policy);
+            }
+            if let Some(Ok(after)) = entry.option("after").map(ready::parse_after) {
+                process.set_after(after);
+            }
+            process.set_ready(entry.option("ready").and_then(|i| i.parse().ok()));
+        }
+        Ok(self)
+    }
+
+    // Later files override keys from earlier ones.
+    pub fn load_env(&mut self, filename: &str) -> io::Result<&mut Engine> {
+        let env = Env::new(filename)?;
+        for (key, val) in env.entries().iter() {
+            self.env.insert(key.to_string(), val.to_string());
+        }
+        Ok(self)
+    }
+
+    pub fn process_names(&self) -> Vec<&str> {
+        self.processes
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    pub fn process(&self, name: &str) -> Option<&Process> {
+        self.processes
+            .iter()
+            .find(|(process_name, _)| process_name == name)
+            .map(|(_, process)| process)
+    }
+
+    pub fn process_mut(&mut self, name: &str) -> Option<&mut Process> {
+        self.processes
+            .iter_mut()
+            .find(|(process_name, _)| process_name == name)
+            .map(|(_, process)| process)
+    }
+
+    pub fn root(&self) -> String {
+        match &self.options.root {
+            Some(i) => i.clone(),
+            None => env::current_dir()
+                .unwrap()
+                .into_os_string()
+                .into_string()
+                .unwrap(),
+        }
+    }
+
+    // Each process type gets a block of 100 ports in registration order,
+    // one port per instance within the block.
+    pub fn port_for(&self, process: usize, instance: usize) -> u32 {
+        self.base_port() + (process as u32 * 100) + (instance as u32 - 1)
+    }
+
+    pub fn base_port(&self) -> u32 {
+        let ambient = env::var("PORT").ok();
+        self.options
+            .port
+            .or_else(|| self.env.get("PORT").and_then(|i| i.parse().ok()))
+            .or_else(|| ambient.and_then(|i| i.parse().ok()))
+            .unwrap_or(5000)
+    }
+
+    // Runs every process until one of them exits or a shutdown is requested,
+    // and returns the status of the first child that failed on its own.
+    // Meanwhile the process is a child subreaper on Linux, so orphans from
+    // other children of an embedding host are adopted as well. The engine
+    // only reaps its own, the host has to wait for the others.
+    pub fn start(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.register_signal_handlers()?;
+        set_subreaper(true);
+        let names = self.instance_names();
+        self.output.lock().unwrap().startup(&names);
+        // Instances that did start are still shut down when a later one fails to.
+        let spawned = self.spawn_processes();
+        if spawned.is_ok() {
+            self.wait_for_shutdown_or_child_termination();
+        }
+        self.terminate_gracefully();
+        set_subreaper(false);
+        self.flush_readers();
+        self.output.lock().unwrap().shutdown();
+        spawned?;
+        Ok(self.exitstatus)
+    }
+
+    // How every instance ended, in the order they were reaped.
+    pub fn exit_statuses(&self) -> &[(String, ExitStatus)] {
+        &self.exit_statuses
+    }
+
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exitstatus
+    }
+
+    // The handlers only raise a flag and wake the main loop through the
+    // self-pipe (ala djb: http://cr.yp.to/docs/selfpipe.html), the signal is
+    // then handled by handle_signals outside of the handler.
+    fn register_signal_handlers(&mut self) -> io::Result<()> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        register_fallback_actions()?;
+        for (signal, flag) in self.signals.iter() {
+            let flag_id = signal_hook::flag::register(*signal, flag.clone())?;
+            self.signal_ids.push(flag_id);
+            let pipe_id = signal_hook::pipe::register(*signal, writer.try_clone()?)?;
+            self.signal_ids.push(pipe_id);
+        }
+        ENGINES.fetch_add(1, Ordering::SeqCst);
+        self.selfpipe = Some(reader);
+        Ok(())
+    }
+
+    // Once no engine is left, the fallback actions take the signals again.
+    fn restore_default_signal_handlers(&mut self) {
+        ENGINES.fetch_sub(1, Ordering::SeqCst);
+        for id in self.signal_ids.drain(..) {
+            signal_hook::unregister(id);
+        }
+        self.selfpipe = None;
+    }
+
+    // Another TERM, INT or HUP while shutting down means not to wait any longer.
+    fn shutdown_signal(&mut self) -> Option<libc::c_int> {
+        self.signals
+            .iter()
+            .filter(|(signal, _)| matches!(*signal, libc::SIGTERM | libc::SIGINT | libc::SIGHUP))
+            .find(|(_, flag)| flag.swap(false, Ordering::SeqCst))
+            .map(|(signal, _)| *signal)
+    }
+
+    // Wait until a signal arrives or the timeout passes.
+    fn read_self_pipe(&mut self, timeout: Duration) {
+        match &mut self.selfpipe {
+            Some(reader) => {
+                let mut fds = libc::pollfd {
+                    fd: reader.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                unsafe {
+                    libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int);
+                }
+                let mut buffer = [0; 11];
+                while let Ok(n) = reader.read(&mut buffer) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+            None => thread::sleep(timeout),
+        }
+    }
+
+    fn handle_signals(&mut self) {
+        let pending: Vec<libc::c_int> = self
+            .signals
+            .iter()
+            .filter(|(_, flag)| flag.swap(false, Ordering::SeqCst))
+            .map(|(signal, _)| *signal)
+            .collect();
+        for signal in pending {
+            self.handle_signal(signal);
+        }
+    }
+
+    fn handle_signal(&mut self, signal: libc::c_int) {
+        match signal {
+            // Our terminal was resized, pass the new size on to the children's
+            libc::SIGWINCH => self.resize_ptys(),
+            libc::SIGTERM | libc::SIGINT | libc::SIGHUP => {
+                self.system(&format!(
+                    "SIG{} received, starting shutdown",
+                    signal_name(signal)
+                ));
+                self.shutdown.store(true, Ordering::SeqCst);
+            }
+            _ => {
+                self.system(&format!(
+                    "SIG{} received, forwarding it to children",
+                    signal_name(signal)
+                ));
+                self.kill_children(signal);
+            }
+        }
+    }
+
+    fn resize_ptys(&self) {
+        if let Some(size) = crate::pty::window_size() {
+            for master in self.running.values().filter_map(|i| i.pty.as_ref()) {
+                // The child may have closed its terminal already
+                let _ = crate::pty::set_window_size(master, &size);
+            }
+        }
+    }
+
+    pub fn kill_children(&mut self, signal: i32) {
+        let pids: Vec<u32> = self.running.keys().cloned().collect();
+        for pid in pids {
+            let name = self.name_for(&self.running[&pid]);
+            self.system(&format!(
+                "sending SIG{} to {} at pid {}",
+                signal_name(signal),
+                name,
+                pid
+            ));
+            if let Some(running) = self.running.get_mut(&pid) {
+                running.signalled = Some(signal);
+            }
+            // ESRCH and EPERM are expected for children that are already gone.
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), signal);
+            }
+        }
+    }
+
+    fn signal_groups(&mut self, signal: libc::c_int) {
+        for (pgid, name) in self.groups.clone() {
+            if unsafe { libc::kill(-(pgid as libc::pid_t), signal) } == 0 {
+                self.system(&format!(
+                    "sending SIG{} to what {} left behind in process group {}",
+                    signal_name(signal),
+                    name,
+                    pgid
+                ));
+            }
+        }
+    }
+
+    // Reaps the orphans adopted as subreaper and forgets the groups that
+    // have nothing left in them. Orphans keep their process group, so waiting
+    // on the group of an exited child only collects what it left behind. Its
+    // pgid cannot be handed to a new process while anything is left in it.
+    fn collect_leftovers(&mut self) {
+        let running = &self.running;
+        self.groups.retain(|(pgid, _)| {
+            if running.contains_key(pgid) {
+                return false;
+            }
+            let pgid = *pgid as libc::pid_t;
+            unsafe {
+                while libc::waitpid(-pgid, &mut 0, libc::WNOHANG) > 0 {}
+                libc::kill(-pgid, 0) == 0
+            }
+        });
+    }
+
+    // Every instance the formation asks for, as in "web.1".
+    pub fn instance_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (index, (name, _)) in self.processes.iter().enumerate() {
+            for instance in 1..=self.options.formation.count(name) {
+                names.push(self.name_for_index(index, instance));
+            }
+        }
+        names
+    }
+
+    fn spawn_processes(&mut self) -> io::Result<()> {
+        if self.options.strict_env {
+            self.check_commands()?;
+        }
+        self.pending = self.start_order()?;
+        self.start_ready_processes()?;
+        for index in self.pending.clone() {
+            let waiting: Vec<&str> = self
+                .dependencies(index)
+                .into_iter()
+                .filter(|i| !self.process_ready(*i))
+                .map(|i| self.processes[i].0.as_str())
+                .collect();
+            self.system(&format!(
+                "{} waits for {} to be ready",
+                self.processes[index].0,
+                waiting.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    // Processes come after the ones they depend on, otherwise in the order
+    // they were registered.
+    fn start_order(&self) -> io::Result<Vec<usize>> {
+        for (_, process) in self.processes.iter() {
+            for name in process.after() {
+                if self.processes.iter().all(|(i, _)| i != name) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("process {:?} in after is not defined", name),
+                    ));
+                }
+            }
+        }
+        let after: Vec<Vec<usize>> = (0..self.processes.len())
+            .map(|i| self.dependencies(i))
+            .collect();
+        ready::start_order(&after).map_err(|cycle| {
+            let names: Vec<&str> = cycle
+                .iter()
+                .map(|i| self.processes[*i].0.as_str())
+                .collect();
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("dependency cycle: {}", names.join(" -> ")),
+            )
+        })
+    }
+
+    // Unknown dependencies are refused by start_order before processes start.
+    fn dependencies(&self, index: usize) -> Vec<usize> {
+        self.processes[index]
+            .1
+            .after()
+            .iter()
+            .filter_map(|name| self.processes.iter().position(|(i, _)| i == name))
+            .collect()
+    }
+
+    // A process the formation does not run is never waited for.
+    fn process_ready(&self, index: usize) -> bool {
+        let count = self.options.formation.count(&self.processes[index].0);
+        (1..=count).all(|instance| self.ready.contains(&(index, instance)))
+    }
+
+    fn start_ready_processes(&mut self) -> io::Result<()> {
+        loop {
+            self.check_readiness();
+            let next = self.pending.iter().position(|i| {
+                self.dependencies(*i)
+                    .into_iter()
+                    .all(|i| self.process_ready(i))
+            });
+            let index = match next {
+                Some(next) => self.pending.remove(next),
+                None => return Ok(()),
+            };
+            for instance in 1..=self.options.formation.count(&self.processes[index].0) {
+                self.spawn(index, instance, 0)?;
+            }
+        }
+    }
+
+    // An instance stays ready once it has been, even when it is restarted.
+    fn check_readiness(&mut self) {
+        let newly_ready: Vec<(usize, usize)> = self
+            .running
+            .values()
+            .filter(|i| i.ready.load(Ordering::SeqCst))
+            .map(|i| (i.process, i.instance))
+            .filter(|i| !self.ready.contains(i))
+            .collect();
+        for (process, instance) in newly_ready {
+            self.ready.insert((process, instance));
+            if self.processes[process].1.ready().is_some() {
+                self.output(&self.name_for_index(process, instance), "is ready");
+            }
+        }
+    }
+
+    fn spawn(&mut self, index: usize, instance: usize, restarts: u32) -> io::Result<()> {
+        let env = self.instance_env(index, instance);
+        let name = self.name_for_index(index, instance);
+        let cwd = self.process_dir(index);
+        let mut child = self.processes[index]
+            .1
+            .run_in(&cwd, Some(env.clone()))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+        let probe = self.processes[index].1.ready().cloned();
+        let ready = Arc::new(AtomicBool::new(probe.is_none()));
+        let log_probe = match probe {
+            Some(Probe::Log(pattern)) => Some((pattern, ready.clone())),
+            Some(probe) => {
+                watch_for_readiness(probe, cwd, env, &ready);
+                None
+            }
+            None => None,
+        };
+        let pid = child.id();
+        let pty = match (self.processes[index].1.pty(), &child.stdout) {
+            (true, Some(stdout)) => Some(stdout.as_fd().try_clone_to_owned()?),
+            _ => None,
+        };
+        if let Some(stdout) = child.stdout.take() {
+            self.watch_for_output(name.clone(), stdout, false, log_probe.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.watch_for_output(name.clone(), stderr, true, log_probe);
+        }
+        self.output(&name, &format!("started with pid {}", pid));
+        self.running.insert(
+            pid,
+            Running {
+                child,
+                process: index,
+                instance,
+                signalled: None,
+                pty,
+                started: Instant::now(),
+                restarts,
+                ready,
+            },
+        );
+        Ok(())
+    }
+
+    // Checked for every instance before any is spawned, so nothing has to be
+    // torn down when a variable is missing.
+    fn check_commands(&self) -> io::Result<()> {
+        for index in 0..self.processes.len() {
+            for instance in 1..=self.options.formation.count(&self.processes[index].0) {
+                let env = self.instance_env(index, instance);
+                if let Err(e) = self.processes[index].1.check_command(Some(&env)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: {}", self.name_for_index(index, instance), e),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn instance_env(&self, process: usize, instance: usize) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        env.insert(
+            "PORT".to_string(),
+            self.port_for(process, instance).to_string(),
+        );
+        env.insert("PS".to_string(), self.name_for_index(process, instance));
+        env
+    }
+
+    fn wait_for_shutdown_or_child_termination(&mut self) {
+        loop {
+            self.handle_signals();
+            // Stop if it is time to shut down (asked via a signal or the shutdown handle)
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            // Stop if any of the children died and is not going to be restarted
+            if let Some((running, status)) = self.reap_child() {
+                if !self.schedule_restart(running, status) {
+                    break;
+                }
+            }
+            self.collect_leftovers();
+            let started = self
+                .spawn_due_restarts()
+                .and_then(|_| self.start_ready_processes());
+            if let Err(e) = started {
+                self.system(&e.to_string());
+                break;
+            }
+            self.read_self_pipe(Duration::from_millis(100));
+        }
+    }
+
+    fn check_for_termination(&mut self) -> Option<u32> {
+        let (running, status) = self.reap_child()?;
+        self.record_termination(&running, status, false);
+        Some(running.child.id())
+    }
+
+    // Removes a child that has died off from the running ones. Waits with
+    // WUNTRACED, which try_wait does not, to hear about stopped children too.
+    fn reap_child(&mut self) -> Option<(Running, ExitStatus)> {
+        let pids: Vec<u32> = self.running.keys().cloned().collect();
+        for pid in pids {
+            let mut status = 0;
+            let options = libc::WNOHANG | libc::WUNTRACED;
+            if unsafe { libc::waitpid(pid as libc::pid_t, &mut status, options) } != pid as i32 {
+                continue;
+            }
+            let status = ExitStatus::from_raw(status);
+            if let Some(signal) = status.stopped_signal() {
+                self.child_stopped(pid, signal);
+                continue;
+            }
+            return Some((self.running.remove(&pid)?, status));
+        }
+        None
+    }
+
+    // A child stopped for reading from or writing to the terminal would never
+    // go on, so it is killed. Other stops are only reported.
+    fn child_stopped(&mut self, pid: u32, signal: libc::c_int) {
+        let name = self.name_for(&self.running[&pid]);
+        match signal {
+            libc::SIGTTIN | libc::SIGTTOU => {
+                self.output(
+                    &name,
+                    &format!("stopped by SIG{}, killing it", signal_name(signal)),
+                );
+                unsafe {
+                    libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+                }
+            }
+            _ => self.output(&name, &format!("stopped by SIG{}", signal_name(signal))),
+        }
+    }
+
+    // Returns false when the instance is not restarted and everything should
+    // shut down instead.
+    fn schedule_restart(&mut self, running: Running, status: ExitStatus) -> bool {
+        let name = self.name_for(&running);
+        let policy = self
+            .options
+            .restart
+            .policy(&self.processes[running.process].0);
+        if !policy.restarts(&status) {
+            self.record_termination(&running, status, false);
+            return false;
+        }
+        let backoff = &self.options.backoff;
+        let restart = match backoff.next_restart(running.restarts, running.started.elapsed()) {
+            Some(restart) => restart,
+            None => {
+                self.record_termination(&running, status, false);
+                self.system(&format!("no restarts left for {}, giving up", name));
+                return false;
+            }
+        };
+        let delay = backoff.delay_for(restart);
+        let max_restarts = backoff.max_restarts;
+        self.record_termination(&running, status, true);
+        self.system(&format!(
+            "restarting {} in {:?} (restart {} of {})",
+            name, delay, restart, max_restarts
+        ));
+        self.restarts.push(Restart {
+            process: running.process,
+            instance: running.instance,
+            restart,
+            at: Instant::now() + delay,
+            status,
+        });
+        true
+    }
+
+    fn spawn_due_restarts(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.restarts)
+            .into_iter()
+            .partition(|restart| restart.at <= now);
+        self.restarts = waiting;
+        for restart in due {
+            if let Err(e) = self.spawn(restart.process, restart.instance, restart.restart) {
+                // The failure that made it restart is what the run ends with
+                if !restart.status.success() && self.exitstatus.is_none() {
+                    self.exitstatus = Some(restart.status);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // A child that is going to be restarted does not fail the engine.
+    fn record_termination(&mut self, running: &Running, status: ExitStatus, restarting: bool) {
+        let name = self.name_for(running);
+        self.output(&name, &termination_message_for(&status));
+        // Children that die from the signal we sent them did not fail on their own
+        let expected = running.signalled.is_some() && status.signal() == running.signalled;
+        if !status.success() && !expected && !restarting && self.exitstatus.is_none() {
+            self.exitstatus = Some(status);
+        }
+        self.groups.push((running.child.id(), name.clone()));
+        self.exit_statuses.push((name, status));
+    }
+
+    fn terminate_gracefully(&mut self) {
+        self.stop_children();
+        self.restore_default_signal_handlers();
+    }
+
+    fn stop_children(&mut self) {
+        // Tell all children, and whatever the ones that already exited left
+        // behind, to stop gracefully
+        self.kill_children(libc::SIGTERM);
+        self.collect_leftovers();
+        self.signal_groups(libc::SIGTERM);
+
+        // Wait for all children to stop or until the time comes to kill them all
+        let start_time = Instant::now();
+        while start_time.elapsed() <= Duration::from_secs(self.options.timeout) {
+            while self.check_for_termination().is_some() {}
+            self.collect_leftovers();
+            if self.running.is_empty() && self.groups.is_empty() {
+                return;
+            }
+            if let Some(signal) = self.shutdown_signal() {
+                self.system(&format!(
+                    "SIG{} received during shutdown, killing all processes",
+                    signal_name(signal)
+                ));
+                break;
+            }
+            self.read_self_pipe(Duration::from_millis(100));
+        }
+
+        // Ok, we have no other option than to kill all of our children
+        self.kill_children(libc::SIGKILL);
+        self.signal_groups(libc::SIGKILL);
+        let running = std::mem::take(&mut self.running);
+        for (_, mut running) in running {
+            if let Ok(status) = running.child.wait() {
+                self.record_termination(&running, status, false);
+            }
+        }
+        let start_time = Instant::now();
+        while !self.groups.is_empty() && start_time.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(10));
+            self.collect_leftovers();
+        }
+    }
+
+    // Each stream is read line by line on its own thread, every line is
+    // written while holding the output lock so lines never interleave. A log
+    // probe raises its flag on the first line that matches.
+    fn watch_for_output<R: Read + Send + 'static>(
+        &mut self,
+        name: String,
+        reader: R,
+        stderr: bool,
+        probe: Option<(Regex, Arc<AtomicBool>)>,
+    ) {
+        let output = self.output.clone();
+        self.readers.push(thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+            while let Ok(n) = reader.read_until(b'\n', &mut line) {
+                if n == 0 {
+                    break;
+                }
+                let data = String::from_utf8_lossy(&line);
+                if let Some((pattern, ready)) = &probe {
+                    if pattern.is_match(&data) {
+                        ready.store(true, Ordering::SeqCst);
+                    }
+                }
+                match stderr {
+                    true => output.lock().unwrap().error_output(&name, &data),
+                    false => output.lock().unwrap().output(&name, &data),
+                }
+                line.clear();
+            }
+        }));
+    }
+
+    // Give the readers a moment to drain what the children wrote last. A
+    // reader only blocks past that if a grandchild still holds the pipe.
+    fn flush_readers(&mut self) {
+        let start_time = Instant::now();
+        while self.readers.iter().any(|reader| !reader.is_finished())
+            && start_time.elapsed() < Duration::from_secs(1)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for reader in std::mem::take(&mut self.readers) {
+            if reader.is_finished() {
+                let _ = reader.join();
+            }
+        }
+    }
+
+    fn output(&self, name: &str, data: &str) {
+        self.output.lock().unwrap().output(name, data);
+    }
+
+    fn system(&self, message: &str) {
+        self.output("system", message);
+    }
+
+    fn name_for(&self, running: &Running) -> String {
+        self.name_for_index(running.process, running.instance)
+    }
+
+    fn process_dir(&self, index: usize) -> String {
+        let root = self.root();
+        match self.processes[index].1.cwd().as_str() {
+            "." => root,
+            cwd => Path::new(&root).join(cwd).to_string_lossy().to_string(),
+        }
+    }
+
+    fn name_for_index(&self, process: usize, instance: usize) -> String {
+        format!("{}.{}", self.processes[process].0, instance)
+    }
 }
 
-/*
-require "foreman"
-require "foreman/env"
-require "foreman/process"
-require "foreman/procfile"
-require "tempfile"
-require "fileutils"
-require "thread"
-
-class Foreman::Engine
-
-# The signals that the engine cares about.
-#
-HANDLED_SIGNALS = [ :TERM, :INT, :HUP, :USR1, :USR2 ]
-
-attr_reader :env
-attr_reader :options
-attr_reader :processes
-
-# Create an +Engine+ for running processes
-#
-# @param [Hash] options
-#
-# @option options [String] :formation (all=1)    The process formation to use
-# @option options [Fixnum] :port      (5000)     The base port to assign to processes
-# @option options [String] :root      (Dir.pwd)  The root directory from which to run processes
-#
-def initialize(options={})
-@options = options.dup
-
-@options[:formation] ||= "all=1"
-@options[:timeout] ||= 5
-
-@env       = {}
-@mutex     = Mutex.new
-@names     = {}
-@processes = []
-@running   = {}
-@readers   = {}
-@shutdown  = false
-
-# Self-pipe for deferred signal-handling (ala djb: http://cr.yp.to/docs/selfpipe.html)
-reader, writer       = create_pipe
-reader.close_on_exec = true if reader.respond_to?(:close_on_exec)
-writer.close_on_exec = true if writer.respond_to?(:close_on_exec)
-@selfpipe            = { :reader => reader, :writer => writer }
-
-# Set up a global signal queue
-# http://blog.rubybestpractices.com/posts/ewong/016-Implementing-Signal-Handlers.html
-Thread.main[:signal_queue] = []
-end
-
-# Start the processes registered to this +Engine+
-#
-def start
-register_signal_handlers
-startup
-spawn_processes
-watch_for_output
-sleep 0.1
-wait_for_shutdown_or_child_termination
-shutdown
-exit(@exitstatus) if @exitstatus
-end
-
-# Set up deferred signal handlers
-#
-def register_signal_handlers
-HANDLED_SIGNALS.each do |sig|
-if ::Signal.list.include? sig.to_s
-trap(sig) { Thread.main[:signal_queue] << sig ; notice_signal }
-end
-end
-end
-
-# Unregister deferred signal handlers
-#
-def restore_default_signal_handlers
-HANDLED_SIGNALS.each do |sig|
-trap(sig, :DEFAULT) if ::Signal.list.include? sig.to_s
-end
-end
-
-# Wake the main thread up via the selfpipe when there's a signal
-#
-def notice_signal
-@selfpipe[:writer].write_nonblock( '.' )
-rescue Errno::EAGAIN
-# Ignore writes that would block
-rescue Errno::EINTR
-# Retry if another signal arrived while writing
-retry
-end
-
-# Invoke the real handler for signal +sig+. This shouldn't be called directly
-# by signal handlers, as it might invoke code which isn't re-entrant.
-#
-# @param [Symbol] sig  the name of the signal to be handled
-#
-def handle_signal(sig)
-case sig
-when :TERM
-handle_term_signal
-when :INT
-handle_interrupt
-when :HUP
-handle_hangup
-when *HANDLED_SIGNALS
-handle_signal_forward(sig)
-else
-system "unhandled signal #{sig}"
-end
-end
-
-# Handle a TERM signal
-#
-def handle_term_signal
-system "SIGTERM received, starting shutdown"
-@shutdown = true
-end
-
-# Handle an INT signal
-#
-def handle_interrupt
-system "SIGINT received, starting shutdown"
-@shutdown = true
-end
-
-# Handle a HUP signal
-#
-def handle_hangup
-system "SIGHUP received, starting shutdown"
-@shutdown = true
-end
-
-def handle_signal_forward(signal)
-system "#{signal} received, forwarding it to children"
-kill_children signal
-end
-
-# Register a process to be run by this +Engine+
-#
-# @param [String] name     A name for this process
-# @param [String] command  The command to run
-# @param [Hash]   options
-#
-# @option options [Hash] :env  A custom environment for this process
-#
-def register(name, command, options={})
-options[:env] ||= env
-options[:cwd] ||= File.dirname(command.split(" ").first)
-process = Foreman::Process.new(command, options)
-@names[process] = name
-@processes << process
-end
-
-# Clear the processes registered to this +Engine+
-#
-def clear
-@names     = {}
-@processes = []
-end
-
-# Register processes by reading a Procfile
-#
-# @param [String] filename  A Procfile from which to read processes to register
-#
-def load_procfile(filename)
-options[:root] ||= File.dirname(filename)
-Foreman::Procfile.new(filename).entries do |name, command|
-register name, command, :cwd => options[:root]
-end
-self
-end
-
-# Load a .env file into the +env+ for this +Engine+
-#
-# @param [String] filename  A .env file to load into the environment
-#
-def load_env(filename)
-Foreman::Env.new(filename).entries do |name, value|
-@env[name] = value
-end
-end
-
-# Send a signal to all processes started by this +Engine+
-#
-# @param [String] signal  The signal to send to each process
-#
-def kill_children(signal="SIGTERM")
-if Foreman.windows?
-@running.each do |pid, (process, index)|
-system "sending #{signal} to #{name_for(pid)} at pid #{pid}"
-begin
-Process.kill(signal, pid)
-rescue Errno::ESRCH, Errno::EPERM
-end
-end
-else
-begin
-pids = @running.keys.compact
-Process.kill signal, *pids unless pids.empty?
-rescue Errno::ESRCH, Errno::EPERM
-end
-end
-end
-
-# Send a signal to the whole process group.
-#
-# @param [String] signal  The signal to send
-#
-def killall(signal="SIGTERM")
-if Foreman.windows?
-kill_children(signal)
-else
-begin
-Process.kill "-#{signal}", Process.pid
-rescue Errno::ESRCH, Errno::EPERM
-end
-end
-end
-
-# Get the process formation
-#
-# @returns [Fixnum]  The formation count for the specified process
-#
-def formation
-@formation ||= parse_formation(options[:formation])
-end
-
-# List the available process names
-#
-# @returns [Array]  A list of process names
-#
-def process_names
-@processes.map { |p| @names[p] }
-end
-
-# Get the +Process+ for a specifid name
-#
-# @param [String] name  The process name
-#
-# @returns [Foreman::Process]  The +Process+ for the specified name
-#
-def process(name)
-@names.invert[name]
-end
-
-# Yield each +Process+ in order
-#
-def each_process
-process_names.each do |name|
-yield name, process(name)
-end
-end
-
-# Get the root directory for this +Engine+
-#
-# @returns [String]  The root directory
-#
-def root
-File.expand_path(options[:root] || Dir.pwd)
-end
-
-# Get the port for a given process and offset
-#
-# @param [Foreman::Process] process   A +Process+ associated with this engine
-# @param [Fixnum]           instance  The instance of the process
-#
-# @returns [Fixnum] port  The port to use for this instance of this process
-#
-def port_for(process, instance, base=nil)
-if base
-base + (@processes.index(process.process) * 100) + (instance - 1)
-else
-base_port + (@processes.index(process) * 100) + (instance - 1)
-end
-end
-
-# Get the base port for this foreman instance
-#
-# @returns [Fixnum] port  The base port
-#
-def base_port
-(options[:port] || env["PORT"] || ENV["PORT"] || 5000).to_i
-end
-
-# deprecated
-def environment
-env
-end
-
-private
-
-### Engine API ######################################################
-
-def startup
-raise TypeError, "must use a subclass of Foreman::Engine"
-end
-
-def output(name, data)
-raise TypeError, "must use a subclass of Foreman::Engine"
-end
-
-def shutdown
-raise TypeError, "must use a subclass of Foreman::Engine"
-end
-
-## Helpers ##########################################################
-
-def create_pipe
-IO.method(:pipe).arity.zero? ? IO.pipe : IO.pipe("BINARY")
-end
-
-def name_for(pid)
-process, index = @running[pid]
-name_for_index(process, index)
-end
-
-def name_for_index(process, index)
-[ @names[process], index.to_s ].compact.join(".")
-end
-
-def parse_formation(formation)
-pairs = formation.to_s.gsub(/\s/, "").split(",")
-
-pairs.inject(Hash.new(0)) do |ax, pair|
-process, amount = pair.split("=")
-process == "all" ? ax.default = amount.to_i : ax[process] = amount.to_i
-ax
-end
-end
-
-def output_with_mutex(name, message)
-@mutex.synchronize do
-output name, message
-end
-end
-
-def system(message)
-output_with_mutex "system", message
-end
-
-def termination_message_for(status)
-if status.exited?
-"exited with code #{status.exitstatus}"
-elsif status.signaled?
-"terminated by SIG#{Signal.list.invert[status.termsig]}"
-else
-"died a mysterious death"
-end
-end
-
-def flush_reader(reader)
-until reader.eof?
-data = reader.gets
-output_with_mutex name_for(@readers.key(reader)), data
-end
-end
-
-## Engine ###########################################################
-
-def spawn_processes
-@processes.each do |process|
-1.upto(formation[@names[process]]) do |n|
-reader, writer = create_pipe
-begin
-pid = process.run(:output => writer, :env => {
-"PORT" => port_for(process, n).to_s,
-"PS" => name_for_index(process, n)
-})
-writer.puts "started with pid #{pid}"
-rescue Errno::ENOENT
-writer.puts "unknown command: #{process.command}"
-end
-@running[pid] = [process, n]
-@readers[pid] = reader
-end
-end
-end
-
-def read_self_pipe
-@selfpipe[:reader].read_nonblock(11)
-rescue Errno::EAGAIN, Errno::EINTR, Errno::EBADF, Errno::EWOULDBLOCK
-# ignore
-end
-
-def handle_signals
-while sig = Thread.main[:signal_queue].shift
-self.handle_signal(sig)
-end
-end
-
-def handle_io(readers)
-readers.each do |reader|
-next if reader == @selfpipe[:reader]
-
-if reader.eof?
-@readers.delete_if { |key, value| value == reader }
-else
-data = reader.gets
-output_with_mutex name_for(@readers.invert[reader]), data
-end
-end
-end
-
-def watch_for_output
-Thread.new do
-begin
-loop do
-io = IO.select([@selfpipe[:reader]] + @readers.values, nil, nil, 30)
-read_self_pipe
-handle_signals
-handle_io(io ? io.first : [])
-end
-rescue Exception => ex
-puts ex.message
-puts ex.backtrace
-end
-end
-end
-
-def wait_for_shutdown_or_child_termination
-loop do
-# Stop if it is time to shut down (asked via a signal)
-break if @shutdown
-
-# Stop if any of the children died
-break if check_for_termination
-
-# Sleep for a moment and do not blow up if any signals are coming our way
-begin
-sleep(1)
-rescue Exception
-# noop
-end
-end
-
-# Ok, we have exited from the main loop, time to shut down gracefully
-terminate_gracefully
-end
-
-def check_for_termination
-# Check if any of the children have died off
-pid, status = begin
-Process.wait2(-1, Process::WNOHANG)
-rescue Errno::ECHILD
-return nil
-end
-
-# record the exit status
-@exitstatus ||= status.exitstatus if status
-
-# If no childred have died, nothing to do here
-return nil unless pid
-
-# Log the information about the process that exited
-output_with_mutex name_for(pid), termination_message_for(status)
-
-# Delete it from the list of running processes and return its pid
-@running.delete(pid)
-return pid
-end
-
-def terminate_gracefully
-restore_default_signal_handlers
-
-# Tell all children to stop gracefully
-if Foreman.windows?
-system  "sending SIGKILL to all processes"
-kill_children "SIGKILL"
-else
-system  "sending SIGTERM to all processes"
-kill_children "SIGTERM"
-end
-
-# Wait for all children to stop or until the time comes to kill them all
-start_time = Time.now
-while Time.now - start_time <= options[:timeout]
-return if @running.empty?
-check_for_termination
-
-# Sleep for a moment and do not blow up if more signals are coming our way
-begin
-sleep(0.1)
-rescue Exception
-# noop
-end
-end
-
-# Ok, we have no other option than to kill all of our children
-system  "sending SIGKILL to all processes"
-kill_children "SIGKILL"
-end
-end
- */
+// Checks the probe until it passes or the instance is gone.
+fn watch_for_readiness(
+    probe: Probe,
+    cwd: String,
+    env: HashMap<String, String>,
+    ready: &Arc<AtomicBool>,
+) {
+    let ready = Arc::downgrade(ready);
+    thread::spawn(move || {
+        while let Some(flag) = ready.upgrade() {
+            if probe.check(&cwd, &env) {
+                flag.store(true, Ordering::SeqCst);
+                return;
+            }
+            drop(flag);
+            thread::sleep(PROBE_INTERVAL);
+        }
+    });
+}
+
+// The number of engines that handle signals.
+static ENGINES: AtomicUsize = AtomicUsize::new(0);
+
+// Whether the fallback actions are registered.
+static FALLBACKS: Mutex<bool> = Mutex::new(false);
+
+// signal-hook installs its handler the first time a signal is registered
+// and keeps it for the life of the process, so a signal whose actions are
+// all unregistered is ignored. A permanent action gives a signal that was
+// left at its default back its default effect while no engine is running.
+// Handlers the host installed before are chained by signal-hook itself.
+fn register_fallback_actions() -> io::Result<()> {
+    let mut registered = FALLBACKS.lock().unwrap();
+    if *registered {
+        return Ok(());
+    }
+    for signal in HANDLED_SIGNALS.iter().copied() {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(signal, std::ptr::null(), &mut action) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The default of WINCH is to be ignored.
+        if action.sa_sigaction != libc::SIG_DFL || signal == libc::SIGWINCH {
+            continue;
+        }
+        let fallback = move || {
+            if ENGINES.load(Ordering::SeqCst) == 0 {
+                signal_hook::cleanup::cleanup_raw(signal);
+                unsafe {
+                    libc::raise(signal);
+                }
+            }
+        };
+        unsafe {
+            signal_hook::register(signal, fallback)?;
+        }
+    }
+    *registered = true;
+    Ok(())
+}
+
+// The number of engines running, and whether the process was a subreaper
+// before the first of them made it one.
+static SUBREAPER: Mutex<(usize, bool)> = Mutex::new((0, false));
+
+// While an engine runs, processes orphaned by its children become children
+// of rustman, so they can be waited for and reaped.
+#[cfg(target_os = "linux")]
+fn set_subreaper(on: bool) {
+    let mut state = SUBREAPER.lock().unwrap();
+    match on {
+        true => {
+            if state.0 == 0 {
+                let mut was: libc::c_int = 0;
+                unsafe {
+                    libc::prctl(libc::PR_GET_CHILD_SUBREAPER, &mut was as *mut libc::c_int);
+                    libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
+                }
+                state.1 = was != 0;
+            }
+            state.0 += 1;
+        }
+        false => {
+            state.0 -= 1;
+            if state.0 == 0 && !state.1 {
+                unsafe {
+                    libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 0, 0, 0, 0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_subreaper(_on: bool) {}
+
+fn dirname(filename: &str) -> String {
+    match Path::new(filename).parent() {
+        Some(i) if !i.as_os_str().is_empty() => i.to_string_lossy().to_string(),
+        _ => String::from("."),
+    }
+}
+
+// The directory of a command that starts with an absolute path.
+fn command_dir(command: &str) -> Option<String> {
+    let command = command.strip_prefix("exec:").unwrap_or(command);
+    match command.split_whitespace().next() {
+        Some(program) if program.starts_with('/') => Some(dirname(program)),
+        _ => None,
+    }
+}
+
+pub fn signal_name(signal: libc::c_int) -> String {
+    let name = match signal {
+        libc::SIGHUP => "HUP",
+        libc::SIGINT => "INT",
+        libc::SIGQUIT => "QUIT",
+        libc::SIGILL => "ILL",
+        libc::SIGTRAP => "TRAP",
+        libc::SIGABRT => "ABRT",
+        libc::SIGBUS => "BUS",
+        libc::SIGFPE => "FPE",
+        libc::SIGKILL => "KILL",
+        libc::SIGUSR1 => "USR1",
+        libc::SIGSEGV => "SEGV",
+        libc::SIGUSR2 => "USR2",
+        libc::SIGPIPE => "PIPE",
+        libc::SIGALRM => "ALRM",
+        libc::SIGTERM => "TERM",
+        libc::SIGCHLD => "CHLD",
+        libc::SIGCONT => "CONT",
+        libc::SIGSTOP => "STOP",
+        libc::SIGTSTP => "TSTP",
+        libc::SIGTTIN => "TTIN",
+        libc::SIGTTOU => "TTOU",
+        libc::SIGURG => "URG",
+        libc::SIGXCPU => "XCPU",
+        libc::SIGXFSZ => "XFSZ",
+        libc::SIGVTALRM => "VTALRM",
+        libc::SIGPROF => "PROF",
+        libc::SIGWINCH => "WINCH",
+        libc::SIGIO => "IO",
+        libc::SIGSYS => "SYS",
+        _ => return signal.to_string(),
+    };
+    name.to_string()
+}
+
+pub fn termination_message_for(status: &ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        format!("exited with code {}", code)
+    } else if let Some(signal) = status.signal() {
+        format!("terminated by SIG{}", signal_name(signal))
+    } else {
+        String::from("died a mysterious death")
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    /*
-        require "spec_helper"
-        require "foreman/engine"
-
-        class Foreman::Engine::Tester < Foreman::Engine
-        attr_reader :buffer
-
-        def startup
-        @buffer = ""
-        end
-
-        def output(name, data)
-        @buffer += "#{name}: #{data}"
-        end
-
-        def shutdown
-        end
-        end
-
-        describe "Foreman::Engine", :fakefs do
-        subject do
-        write_procfile "Procfile"
-        Foreman::Engine::Tester.new.load_procfile("Procfile")
-        end
-
-        describe "initialize" do
-        describe "with a Procfile" do
-        before { write_procfile }
-
-    it "reads the processes" do
-    expect(subject.process("alpha").command).to eq("./alpha")
-    expect(subject.process("bravo").command).to eq("./bravo")
-    end
-    end
-    end
-
-    describe "start" do
-    it "forks the processes" do
-    expect(subject.process("alpha")).to receive(:run)
-    expect(subject.process("bravo")).to receive(:run)
-    expect(subject).to receive(:watch_for_output)
-    expect(subject).to receive(:wait_for_shutdown_or_child_termination)
-    subject.start
-    end
-
-    it "handles concurrency" do
-    subject.options[:formation] = "alpha=2"
-    expect(subject.process("alpha")).to receive(:run).twice
-    expect(subject.process("bravo")).to_not receive(:run)
-    expect(subject).to receive(:watch_for_output)
-    expect(subject).to receive(:wait_for_shutdown_or_child_termination)
-    subject.start
-    end
-    end
-
-    describe "directories" do
-    it "has the directory default relative to the Procfile" do
-    write_procfile "/some/app/Procfile"
-    engine = Foreman::Engine.new.load_procfile("/some/app/Procfile")
-    expect(engine.root).to eq("/some/app")
-    end
-    end
-
-    describe "environment" do
-    it "should read env files" do
-    write_file("/tmp/env") { |f| f.puts("FOO=baz") }
-    subject.load_env("/tmp/env")
-    expect(subject.env["FOO"]).to eq("baz")
-    end
-
-    it "should read more than one if specified" do
-    write_file("/tmp/env1") { |f| f.puts("FOO=bar") }
-    write_file("/tmp/env2") { |f| f.puts("BAZ=qux") }
-    subject.load_env "/tmp/env1"
-    subject.load_env "/tmp/env2"
-    expect(subject.env["FOO"]).to eq("bar")
-    expect(subject.env["BAZ"]).to eq("qux")
-    end
-
-    it "should handle quoted values" do
-    write_file("/tmp/env") do |f|
-    f.puts 'FOO=bar'
-    f.puts 'BAZ="qux"'
-    f.puts "FRED='barney'"
-    f.puts 'OTHER="escaped\"quote"'
-    f.puts 'URL="http://example.com/api?foo=bar&baz=1"'
-    end
-    subject.load_env "/tmp/env"
-    expect(subject.env["FOO"]).to   eq("bar")
-    expect(subject.env["BAZ"]).to   eq("qux")
-    expect(subject.env["FRED"]).to  eq("barney")
-    expect(subject.env["OTHER"]).to eq('escaped"quote')
-          expect(subject.env["URL"]).to   eq("http://example.com/api?foo=bar&baz=1")
-    end
-
-    it "should handle multiline strings" do
-    write_file("/tmp/env") do |f|
-    f.puts 'FOO="bar\nbaz"'
-    end
-    subject.load_env "/tmp/env"
-    expect(subject.env["FOO"]).to eq("bar\nbaz")
-    end
-
-    it "should fail if specified and doesnt exist" do
-    expect { subject.load_env "/tmp/env" }.to raise_error(Errno::ENOENT)
-    end
-
-    it "should set port from .env if specified" do
-    write_file("/tmp/env") { |f| f.puts("PORT=9000") }
-    subject.load_env "/tmp/env"
-    expect(subject.send(:base_port)).to eq(9000)
-    end
-    end
-
-    end
-         */
+    use crate::output::BufferSink;
+    use crate::process::StderrMode;
+    use crate::restart::RestartPolicy;
+    use crate::tmpfile::TmpFile;
+
+    static PROCFILE_IN_PATH: &str = "tests/Procfile";
+    static PROCFILE_BAD_PATH: &str = "tests/Procfile.bad";
+    static ENV_PATH: &str = "tests/.env";
+    static ENV_EXTRA_PATH: &str = "tests/.env.extra";
+
+    #[test]
+    fn test_reads_the_processes() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_procfile(PROCFILE_IN_PATH).unwrap();
+        assert_eq!(
+            vec!["echo", "env", "ps", "test", "utf8"],
+            engine.process_names()
+        );
+        assert_eq!(
+            "bin/echo echoing",
+            engine.process("echo").unwrap().command()
+        );
+        assert_eq!("bin/test", engine.process("test").unwrap().command());
+        assert!(engine.process("unicorn").is_none());
+    }
+
+    #[test]
+    fn test_has_the_directory_default_relative_to_the_procfile() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_procfile(PROCFILE_IN_PATH).unwrap();
+        assert_eq!("tests", engine.root());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_procfile(PROCFILE_IN_PATH).unwrap();
+        engine.clear();
+        assert!(engine.process_names().is_empty());
+    }
+
+    #[test]
+    fn test_stops_when_a_child_terminates() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("test", "tests/test.sh", None);
+        engine.register("sleep", "sleep 10", None);
+        let start = Instant::now();
+        engine.start().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(engine.running.is_empty());
+    }
+
+    #[test]
+    fn test_stops_when_asked_to_shut_down() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("sleep", "sleep 10", None);
+        let shutdown = engine.shutdown_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            shutdown.store(true, Ordering::SeqCst);
+        });
+        let start = Instant::now();
+        engine.start().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(engine.running.is_empty());
+    }
+
+    #[test]
+    fn test_handles_concurrency() {
+        let mut engine = Engine::new(EngineOptions {
+            formation: "alpha=2".parse().unwrap(),
+            ..EngineOptions::default()
+        });
+        engine.register("alpha", "sleep 10", None);
+        engine.register("bravo", "sleep 10", None);
+        engine.spawn_processes().unwrap();
+        let mut names: Vec<String> = engine
+            .running
+            .values()
+            .map(|running| engine.name_for(running))
+            .collect();
+        names.sort();
+        assert_eq!(vec!["alpha.1", "alpha.2"], names);
+        engine.terminate_gracefully();
+        assert!(engine.running.is_empty());
+    }
+
+    #[test]
+    fn test_records_the_first_failing_exit_status() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_procfile(PROCFILE_BAD_PATH).unwrap();
+        let status = engine.start().unwrap().unwrap();
+        assert_eq!(Some(1), status.code());
+        assert_eq!(Some(status), engine.exit_status());
+        assert_eq!("bad.1", engine.exit_statuses()[0].0);
+        assert_eq!(2, engine.exit_statuses().len());
+    }
+
+    #[test]
+    fn test_children_stopped_by_the_engine_do_not_fail_it() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("test", "tests/test.sh", None);
+        engine.register("sleep", "sleep 10", None);
+        assert_eq!(None, engine.start().unwrap());
+        assert_eq!(2, engine.exit_statuses().len());
+    }
+
+    #[test]
+    fn test_termination_message_for() {
+        assert_eq!(
+            "exited with code 3",
+            termination_message_for(&ExitStatus::from_raw(3 << 8))
+        );
+        assert_eq!(
+            "terminated by SIGSEGV",
+            termination_message_for(&ExitStatus::from_raw(libc::SIGSEGV))
+        );
+    }
+
+    #[test]
+    fn test_kills_children_that_ignore_sigterm_after_the_timeout() {
+        let mut engine = Engine::new(EngineOptions {
+            timeout: 1,
+            ..EngineOptions::default()
+        });
+        engine.register("stubborn", "trap '' TERM; sleep 10 & wait", None);
+        engine.register("sleep", "sleep 10", None);
+        engine.spawn_processes().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        engine.terminate_gracefully();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(5));
+        assert!(engine.running.is_empty());
+    }
+
+    fn started_pid(buffer: &str, name: &str) -> libc::pid_t {
+        let prefix = format!("{}: started with pid ", name);
+        let start = buffer.find(&prefix).unwrap() + prefix.len();
+        let end = start + buffer[start..].find('\n').unwrap();
+        buffer[start..end].parse().unwrap()
+    }
+
+    #[test]
+    fn test_stops_what_exited_children_leave_behind() {
+        let sink = BufferSink::new();
+        let options = EngineOptions {
+            timeout: 1,
+            ..EngineOptions::default()
+        };
+        let mut engine = Engine::with_sink(options, Box::new(sink.clone()));
+        engine.register("leaver", "sleep 30 & sleep 0.2", None);
+        engine.register(
+            "stubborn",
+            "sh -c \"trap '' TERM; sleep 30\" & sleep 0.2",
+            None,
+        );
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        for name in ["leaver.1", "stubborn.1"] {
+            let pgid = started_pid(&buffer, name);
+            assert_eq!(-1, unsafe { libc::kill(-pgid, 0) }, "{}", buffer);
+        }
+        assert!(
+            buffer.contains("sending SIGKILL to what stubborn.1 left behind"),
+            "{}",
+            buffer
+        );
+    }
+
+    #[test]
+    fn test_reaps_orphans_while_running() {
+        let sink = BufferSink::new();
+        let options = EngineOptions {
+            restart: "always".parse().unwrap(),
+            backoff: Backoff {
+                delay: Duration::from_secs(1),
+                max_restarts: 1,
+                ..Backoff::default()
+            },
+            ..EngineOptions::default()
+        };
+        let mut engine = Engine::with_sink(options, Box::new(sink.clone()));
+        let file = env::temp_dir().join(format!("rustman-orphan-{}", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        // The first run leaves an orphan behind, the second one looks for it
+        let command = format!(
+            "if [ -f {0} ]; then test -e /proc/$(cat {0}) && echo kept || echo reaped; \
+             else sleep 0.2 & echo $! > {0}; fi",
+            file.display()
+        );
+        engine.register("leaver", &command, None);
+        engine.start().unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let buffer = sink.contents();
+        assert!(buffer.contains("leaver.1: reaped"), "{}", buffer);
+        assert!(engine.groups.is_empty());
+        let state = SUBREAPER.lock().unwrap();
+        let mut subreaper: libc::c_int = 0;
+        unsafe {
+            libc::prctl(
+                libc::PR_GET_CHILD_SUBREAPER,
+                &mut subreaper as *mut libc::c_int,
+            )
+        };
+        assert_eq!(state.0 > 0 || state.1, subreaper != 0);
+    }
+
+    #[test]
+    fn test_signals_reach_the_whole_process_group() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("tree", "sleep 30 & sleep 30 & wait", None);
+        engine.register("quick", "sleep 0.2", None);
+        engine.start().unwrap();
+        let pgid = started_pid(&sink.contents(), "tree.1");
+        assert_eq!(-1, unsafe { libc::kill(-pgid, 0) });
+    }
+
+    #[test]
+    fn test_term_int_and_hup_start_a_shutdown() {
+        for signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            let mut engine = Engine::new(EngineOptions::default());
+            engine.handle_signal(*signal);
+            assert!(engine.shutdown.load(Ordering::SeqCst));
+        }
+    }
+
+    // Real signals reach every engine in the process, so the engines run in
+    // a test process of their own.
+    #[test]
+    fn test_handles_signals_again_when_started_twice() {
+        let name = "engine::tests::test_handles_signals_again_when_started_twice";
+        if env::var("RUSTMAN_SIGNAL_TEST").is_err() {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", name, "--nocapture"])
+                .env("RUSTMAN_SIGNAL_TEST", "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("round 2 shut down"), "{}", stdout);
+            // With no engine left, TERM terminates the process again
+            assert_eq!(Some(libc::SIGTERM), output.status.signal(), "{}", stdout);
+            return;
+        }
+        let terminate = || unsafe {
+            libc::kill(libc::getpid(), libc::SIGTERM);
+        };
+        for round in 1..=2 {
+            let sink = BufferSink::new();
+            let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+            engine.register("sleep", "sleep 3", None);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                terminate();
+            });
+            let start = Instant::now();
+            engine.start().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(2));
+            assert!(sink
+                .contents()
+                .contains("SIGTERM received, starting shutdown"));
+            assert!(engine.running.is_empty());
+            println!("round {} shut down", round);
+        }
+        terminate();
+        thread::sleep(Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_a_second_shutdown_signal_kills_at_once() {
+        let sink = BufferSink::new();
+        let options = EngineOptions {
+            timeout: 30,
+            ..EngineOptions::default()
+        };
+        let mut engine = Engine::with_sink(options, Box::new(sink.clone()));
+        engine.register("stubborn", "trap '' TERM; sleep 30 & wait", None);
+        let shutdown = engine.shutdown_handle();
+        let (_, term) = engine
+            .signals
+            .iter()
+            .find(|(signal, _)| *signal == libc::SIGTERM)
+            .unwrap();
+        let term = term.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            shutdown.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(500));
+            term.store(true, Ordering::SeqCst);
+        });
+        let start = Instant::now();
+        engine.start().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(sink
+            .contents()
+            .contains("SIGTERM received during shutdown, killing all processes"));
+    }
+
+    #[test]
+    fn test_kills_children_stopped_by_the_terminal() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("reader", "kill -TTIN $$; sleep 10", None);
+        engine.register("sleep", "sleep 10", None);
+        let start = Instant::now();
+        let status = engine.start().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(Some(libc::SIGKILL), status.signal());
+        assert!(sink
+            .contents()
+            .contains("reader.1: stopped by SIGTTIN, killing it"));
+    }
+
+    #[test]
+    fn test_usr1_is_forwarded_to_children() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("sleep", "sleep 10", None);
+        engine.spawn_processes().unwrap();
+        let (_, flag) = engine
+            .signals
+            .iter()
+            .find(|(signal, _)| *signal == libc::SIGUSR1)
+            .unwrap();
+        flag.store(true, Ordering::SeqCst);
+        engine.handle_signals();
+        assert!(!engine.shutdown.load(Ordering::SeqCst));
+        let start = Instant::now();
+        while engine.check_for_termination().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(engine.running.is_empty());
+    }
+
+    #[test]
+    fn test_signal_name() {
+        assert_eq!("TERM", signal_name(libc::SIGTERM));
+        assert_eq!("SEGV", signal_name(libc::SIGSEGV));
+        assert_eq!("1234", signal_name(1234));
+    }
+
+    #[test]
+    fn test_instance_names() {
+        let mut engine = Engine::new(EngineOptions {
+            formation: "all=1,worker=2,clock=0".parse().unwrap(),
+            ..EngineOptions::default()
+        });
+        engine.register("web", "./web", None);
+        engine.register("worker", "./worker", None);
+        engine.register("clock", "./clock", None);
+        assert_eq!(
+            vec!["web.1", "worker.1", "worker.2"],
+            engine.instance_names()
+        );
+    }
+
+    #[test]
+    fn test_sends_output_to_the_sink() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("test", "tests/test.sh", None);
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        assert!(buffer.contains("test.1: started with pid "), "{}", buffer);
+        assert!(buffer.contains("test.1: testing\n"), "{}", buffer);
+        assert!(
+            buffer.contains("test.1: exited with code 0\n"),
+            "{}",
+            buffer
+        );
+    }
+
+    #[test]
+    fn test_processes_run_in_their_directory() {
+        let sink = BufferSink::new();
+        let options = EngineOptions {
+            root: Some(".".to_string()),
+            ..EngineOptions::default()
+        };
+        let mut engine = Engine::with_sink(options, Box::new(sink.clone()));
+        engine.register("root", "pwd; sleep 1", None);
+        engine.register("sub", "pwd; sleep 1", Some("tests".to_string()));
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        let root = Path::new(".").canonicalize().unwrap();
+        let sub = root.join("tests");
+        assert!(
+            buffer.contains(&format!("root.1: {}\n", root.display())),
+            "{}",
+            buffer
+        );
+        assert!(
+            buffer.contains(&format!("sub.1: {}\n", sub.display())),
+            "{}",
+            buffer
+        );
+    }
+
+    #[test]
+    fn test_directories_are_resolved_when_processes_spawn() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("sub", "pwd; sleep 1", Some("rustman-lib/tests".to_string()));
+        engine.register("absolute", "/bin/pwd; sleep 1", None);
+        engine.options_mut().root = Some("..".to_string());
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        let sub = Path::new("tests").canonicalize().unwrap();
+        let bin = Path::new("/bin").canonicalize().unwrap();
+        assert!(
+            buffer.contains(&format!("sub.1: {}\n", sub.display())),
+            "{}",
+            buffer
+        );
+        assert!(
+            buffer.contains(&format!("absolute.1: {}\n", bin.display())),
+            "{}",
+            buffer
+        );
+
+        // Procfile entries run in the root whatever their command
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        let procfile = TmpFile::write("tests/Procfile.engine", "absolute: /bin/pwd; sleep 1\n");
+        engine.load_procfile(&procfile.filename).unwrap();
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        assert!(
+            buffer.contains(&format!("absolute.1: {}\n", sub.display())),
+            "{}",
+            buffer
+        );
+    }
+
+    #[test]
+    fn test_start_fails_when_a_directory_is_missing() {
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(BufferSink::new()));
+        engine.register("sleep", "sleep 10", None);
+        engine.register("lost", "true", Some("tests/missing".to_string()));
+        let error = engine.start().unwrap_err();
+        assert!(
+            error.to_string().starts_with("lost.1: working directory "),
+            "{}",
+            error
+        );
+        assert!(engine.running.is_empty());
+    }
+
+    #[test]
+    fn test_strict_env_refuses_undefined_variables() {
+        let mut engine = Engine::with_sink(
+            EngineOptions {
+                strict_env: true,
+                ..EngineOptions::default()
+            },
+            Box::new(BufferSink::new()),
+        );
+        engine.register("web", "echo $PORT $PS", None);
+        engine.register("worker", "echo ${RUSTMAN_UNDEFINED}", None);
+        let error = engine.start().unwrap_err();
+        assert_eq!(
+            "worker.1: undefined variable RUSTMAN_UNDEFINED",
+            error.to_string()
+        );
+        assert!(engine.exit_statuses().is_empty());
+    }
+
+    #[test]
+    fn test_sends_stderr_to_the_sink() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("merged", "echo out; echo err >&2; sleep 1", None);
+        engine.register("separate", "echo out; echo err >&2; sleep 1", None);
+        engine
+            .process_mut("separate")
+            .unwrap()
+            .set_stderr(StderrMode::Separate);
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        let out = buffer.find("merged.1: out\n");
+        let err = buffer.find("merged.1: err\n");
+        assert!(out.is_some() && out < err, "{}", buffer);
+        assert!(buffer.contains("separate.1: out\n"), "{}", buffer);
+        assert!(buffer.contains("separate.1 (stderr): err\n"), "{}", buffer);
+    }
+
+    #[test]
+    fn test_reads_annotations() {
+        let mut engine = Engine::new(EngineOptions::default());
+        let contents =
+            "#@ stderr: separate\n#@ cwd: web\nweb: x\n#@ pty: true\n#@ restart: always\nworker: y\n";
+        let procfile = TmpFile::write("tests/Procfile.engine", contents);
+        engine.load_procfile(&procfile.filename).unwrap();
+        assert_eq!(
+            StderrMode::Separate,
+            engine.process("web").unwrap().stderr()
+        );
+        assert_eq!(
+            StderrMode::Merge,
+            engine.process("worker").unwrap().stderr()
+        );
+        assert!(!engine.process("web").unwrap().pty());
+        assert!(engine.process("worker").unwrap().pty());
+        assert_eq!("web", engine.process("web").unwrap().cwd());
+        assert_eq!(".", engine.process("worker").unwrap().cwd());
+        let restart = &engine.options().restart;
+        assert_eq!(RestartPolicy::Never, restart.policy("web"));
+        assert_eq!(RestartPolicy::Always, restart.policy("worker"));
+    }
+
+    #[test]
+    fn test_reads_dependencies() {
+        let mut engine = Engine::new(EngineOptions::default());
+        let contents = "#@ after: db\nweb: x\n#@ ready: tcp:5432\ndb: y\n";
+        let procfile = TmpFile::write("tests/Procfile.engine", contents);
+        engine.load_procfile(&procfile.filename).unwrap();
+        assert_eq!(vec!["db"], engine.process("web").unwrap().after());
+        assert!(engine.process("web").unwrap().ready().is_none());
+        assert!(matches!(
+            engine.process("db").unwrap().ready(),
+            Some(Probe::Tcp(i)) if i == "5432"
+        ));
+        assert_eq!(vec![1, 0], engine.start_order().unwrap());
+    }
+
+    #[test]
+    fn test_starts_processes_once_their_dependencies_are_ready() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        let file = env::temp_dir().join(format!("rustman-ready-{}", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        engine.register("worker", "sleep 0.5", None);
+        engine.register("db", "sleep 0.3; echo accepting connections; sleep 5", None);
+        let command = format!("sleep 0.3; touch {}; sleep 5", file.display());
+        engine.register("redis", &command, None);
+        let db = engine.process_mut("db").unwrap();
+        db.set_ready(Some("log:^accepting".parse().unwrap()));
+        let redis = engine.process_mut("redis").unwrap();
+        redis.set_ready(Some(Probe::File(file.to_string_lossy().to_string())));
+        let worker = engine.process_mut("worker").unwrap();
+        worker.set_after(vec!["db".to_string(), "redis".to_string()]);
+        engine.start().unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let buffer = sink.contents();
+        let position = |text: &str| buffer.find(text).unwrap_or_else(|| panic!("{}", buffer));
+        assert!(buffer.contains("system: worker waits for db, redis to be ready"));
+        assert!(position("db.1: is ready") < position("worker.1: started with pid"));
+        assert!(position("redis.1: is ready") < position("worker.1: started with pid"));
+        assert!(position("redis.1: started with pid") < position("redis.1: is ready"));
+    }
+
+    #[test]
+    fn test_start_fails_on_a_dependency_cycle() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("web", "sleep 10", None);
+        engine.register("worker", "sleep 10", None);
+        engine
+            .process_mut("web")
+            .unwrap()
+            .set_after(vec!["worker".to_string()]);
+        engine
+            .process_mut("worker")
+            .unwrap()
+            .set_after(vec!["web".to_string()]);
+        let error = engine.start().unwrap_err();
+        assert_eq!("dependency cycle: web -> worker -> web", error.to_string());
+        assert!(engine.exit_statuses().is_empty());
+    }
+
+    #[test]
+    fn test_start_fails_on_an_unknown_dependency() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("web", "sleep 10", None);
+        engine
+            .process_mut("web")
+            .unwrap()
+            .set_after(vec!["db".to_string()]);
+        let error = engine.start().unwrap_err();
+        assert_eq!("process \"db\" in after is not defined", error.to_string());
+        assert!(engine.exit_statuses().is_empty());
+    }
+
+    fn restarting_engine(sink: &BufferSink, restart: &str) -> Engine {
+        let options = EngineOptions {
+            restart: restart.parse().unwrap(),
+            backoff: Backoff {
+                delay: Duration::from_millis(50),
+                max_restarts: 2,
+                ..Backoff::default()
+            },
+            ..EngineOptions::default()
+        };
+        Engine::with_sink(options, Box::new(sink.clone()))
+    }
+
+    #[test]
+    fn test_restarts_until_the_restarts_run_out() {
+        let sink = BufferSink::new();
+        let mut engine = restarting_engine(&sink, "flaky=on-failure");
+        engine.register("flaky", "exit 3", None);
+        engine.register("sleep", "sleep 10", None);
+        let status = engine.start().unwrap().unwrap();
+        assert_eq!(Some(3), status.code());
+        let buffer = sink.contents();
+        assert_eq!(3, buffer.matches("flaky.1: started with pid").count());
+        assert!(buffer.contains("restarting flaky.1 in 50ms (restart 1 of 2)"));
+        assert!(buffer.contains("restarting flaky.1 in 100ms (restart 2 of 2)"));
+        assert!(buffer.contains("no restarts left for flaky.1, giving up"));
+        assert_eq!(1, buffer.matches("sleep.1: started with pid").count());
+    }
+
+    #[test]
+    fn test_on_failure_does_not_restart_a_clean_exit() {
+        let sink = BufferSink::new();
+        let mut engine = restarting_engine(&sink, "on-failure");
+        engine.register("done", "true", None);
+        engine.register("sleep", "sleep 10", None);
+        assert_eq!(None, engine.start().unwrap());
+        assert_eq!(
+            1,
+            sink.contents().matches("done.1: started with pid").count()
+        );
+    }
+
+    #[test]
+    fn test_always_restarts_a_clean_exit() {
+        let sink = BufferSink::new();
+        let mut engine = restarting_engine(&sink, "always");
+        engine.register("done", "true", None);
+        let shutdown = engine.shutdown_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            shutdown.store(true, Ordering::SeqCst);
+        });
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        assert!(buffer.contains("restarting done.1 in 50ms (restart 1 of 2)"));
+        assert!(buffer.contains("restarting done.1 in 100ms (restart 2 of 2)"));
+    }
+
+    #[test]
+    fn test_runs_pty_processes_on_a_terminal() {
+        let sink = BufferSink::new();
+        let mut engine = Engine::with_sink(EngineOptions::default(), Box::new(sink.clone()));
+        engine.register("tty", "test -t 1 && echo on a tty; sleep 1", None);
+        engine.register("pipe", "test -t 1 || echo on a pipe; sleep 1", None);
+        engine.process_mut("tty").unwrap().set_pty(true);
+        engine.start().unwrap();
+        let buffer = sink.contents();
+        assert!(buffer.contains("tty.1: on a tty\n"), "{}", buffer);
+        assert!(buffer.contains("pipe.1: on a pipe\n"), "{}", buffer);
+    }
+
+    #[test]
+    fn test_port_for() {
+        let mut engine = Engine::new(EngineOptions {
+            port: Some(9000),
+            ..EngineOptions::default()
+        });
+        engine.register("web", "./web", None);
+        engine.register("worker", "./worker", None);
+        assert_eq!(9000, engine.port_for(0, 1));
+        assert_eq!(9001, engine.port_for(0, 2));
+        assert_eq!(9100, engine.port_for(1, 1));
+        assert_eq!(9102, engine.port_for(1, 3));
+    }
+
+    #[test]
+    fn test_port_blocks_follow_procfile_order() {
+        let mut engine = Engine::new(EngineOptions {
+            port: Some(9000),
+            ..EngineOptions::default()
+        });
+        let procfile = TmpFile::write("tests/Procfile.engine", "worker: y\nweb: x\nclock: z\n");
+        engine.load_procfile(&procfile.filename).unwrap();
+        assert_eq!(vec!["worker", "web", "clock"], engine.process_names());
+        assert_eq!("9000", engine.instance_env(0, 1)["PORT"]);
+        assert_eq!("9100", engine.instance_env(1, 1)["PORT"]);
+        assert_eq!("9200", engine.instance_env(2, 1)["PORT"]);
+        assert_eq!("clock.1", engine.instance_env(2, 1)["PS"]);
+    }
+
+    #[test]
+    fn test_base_port_prefers_the_option_then_the_env() {
+        let mut engine = Engine::new(EngineOptions {
+            port: Some(9000),
+            ..EngineOptions::default()
+        });
+        engine.env.insert("PORT".to_string(), "7000".to_string());
+        assert_eq!(9000, engine.base_port());
+        engine.options.port = None;
+        assert_eq!(7000, engine.base_port());
+    }
+
+    #[test]
+    fn test_instance_env() {
+        let mut engine = Engine::new(EngineOptions {
+            port: Some(5000),
+            ..EngineOptions::default()
+        });
+        engine.register("web", "./web", None);
+        engine.register("worker", "./worker", None);
+        let env = engine.instance_env(1, 2);
+        assert_eq!("5101", env["PORT"]);
+        assert_eq!("worker.2", env["PS"]);
+    }
+
+    #[test]
+    fn test_should_read_more_than_one_env_file() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_env(ENV_PATH).unwrap();
+        engine.load_env(ENV_EXTRA_PATH).unwrap();
+        assert_eq!("bar", engine.env()["FOO"]);
+        assert_eq!("qux", engine.env()["BAZ"]);
+    }
+
+    #[test]
+    fn test_should_fail_if_env_file_doesnt_exist() {
+        let mut engine = Engine::new(EngineOptions::default());
+        assert!(engine.load_env("tests/env.missing").is_err());
+    }
+
+    #[test]
+    fn test_should_set_port_from_env_if_specified() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.load_env(ENV_PATH).unwrap();
+        assert_eq!(9000, engine.base_port());
+    }
+
+    #[test]
+    fn test_instance_env_includes_the_loaded_env() {
+        let mut engine = Engine::new(EngineOptions::default());
+        engine.register("web", "./web", None);
+        engine.load_env(ENV_PATH).unwrap();
+        let env = engine.instance_env(0, 1);
+        assert_eq!("bar", env["FOO"]);
+        assert_eq!("9000", env["PORT"]);
+    }
 }
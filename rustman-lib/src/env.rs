@@ -0,0 +1,112 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::io;
+
+lazy_static! {
+    static ref RE: Regex = Regex::new(r"^([A-Za-z_0-9]+)=(.*)$").expect("Cannot build regexp");
+    static ref SINGLE_QUOTED: Regex = Regex::new(r"^'(.*)'$").expect("Cannot build regexp");
+    static ref DOUBLE_QUOTED: Regex = Regex::new(r#"^"(.*)"$"#).expect("Cannot build regexp");
+    static ref ESCAPED: Regex = Regex::new(r"\\(.)").expect("Cannot build regexp");
+}
+
+#[derive(Debug, Default)]
+pub struct Env {
+    entries: HashMap<String, String>,
+}
+
+impl Env {
+    pub fn new(filename: &str) -> io::Result<Env> {
+        let data = std::fs::read_to_string(filename)?;
+        Ok(Env::parse(&data))
+    }
+
+    pub fn entries(&self) -> &HashMap<String, String> {
+        &self.entries
+    }
+
+    fn parse(data: &str) -> Env {
+        let mut entries = HashMap::new();
+        for line in data.replace("\r\n", "\n").split('\n') {
+            if let Some(cap) = RE.captures(line) {
+                entries.insert(cap[1].to_string(), Env::unquote(&cap[2]));
+            }
+        }
+        Env { entries }
+    }
+
+    fn unquote(value: &str) -> String {
+        if let Some(cap) = SINGLE_QUOTED.captures(value) {
+            // Single quoted values are taken literally
+            cap[1].to_string()
+        } else if let Some(cap) = DOUBLE_QUOTED.captures(value) {
+            // Double quoted values are unescaped, preserving newline characters
+            let value = cap[1].replace("\\n", "\n");
+            ESCAPED.replace_all(&value, "$1").to_string()
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmpfile::TmpFile;
+
+    fn write_env(contents: &str) -> TmpFile {
+        TmpFile::write("tests/env.tmp", contents)
+    }
+
+    // Tests from https://github.com/ddollar/foreman/blob/master/spec/foreman/engine_spec.rb
+    #[test]
+    fn test_should_read_env_files() {
+        let tmpfile = write_env("FOO=baz\n");
+        let env = Env::new(&tmpfile.filename).unwrap();
+        assert_eq!("baz", env.entries()["FOO"]);
+    }
+
+    #[test]
+    fn test_should_handle_quoted_values() {
+        let tmpfile = write_env(concat!(
+            "FOO=bar\n",
+            "BAZ=\"qux\"\n",
+            "FRED='barney'\n",
+            "OTHER=\"escaped\\\"quote\"\n",
+            "URL=\"http://example.com/api?foo=bar&baz=1\"\n",
+        ));
+        let env = Env::new(&tmpfile.filename).unwrap();
+        assert_eq!("bar", env.entries()["FOO"]);
+        assert_eq!("qux", env.entries()["BAZ"]);
+        assert_eq!("barney", env.entries()["FRED"]);
+        assert_eq!("escaped\"quote", env.entries()["OTHER"]);
+        assert_eq!("http://example.com/api?foo=bar&baz=1", env.entries()["URL"]);
+    }
+
+    #[test]
+    fn test_should_handle_multiline_strings() {
+        let tmpfile = write_env("FOO=\"bar\\nbaz\"\n");
+        let env = Env::new(&tmpfile.filename).unwrap();
+        assert_eq!("bar\nbaz", env.entries()["FOO"]);
+    }
+
+    #[test]
+    fn test_single_quotes_are_literal() {
+        let env = Env::parse("FOO='bar\\nbaz'\r\nBAR=a=b\n");
+        assert_eq!("bar\\nbaz", env.entries()["FOO"]);
+        assert_eq!("a=b", env.entries()["BAR"]);
+    }
+
+    #[test]
+    fn test_ignores_lines_that_are_not_assignments() {
+        let env = Env::parse("# comment\n\nexport FOO=bar\nBAR=baz");
+        assert_eq!(1, env.entries().len());
+        assert_eq!("baz", env.entries()["BAR"]);
+    }
+
+    #[test]
+    fn test_should_fail_if_specified_and_doesnt_exist() {
+        let error = Env::new("tests/env.missing").unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+    }
+}
@@ -0,0 +1,295 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+lazy_static! {
+    // Variables a command sets itself: NAME=value, for NAME in, read NAME...
+    static ref ASSIGNMENT: Regex =
+        Regex::new(r"(?:^|[\s;&|(])([A-Za-z_][A-Za-z0-9_]*)=").expect("Cannot build regexp");
+    static ref FOR: Regex =
+        Regex::new(r"\bfor\s+([A-Za-z_][A-Za-z0-9_]*)").expect("Cannot build regexp");
+    static ref READ: Regex =
+        Regex::new(r"\bread((?:\s+-?[A-Za-z_][A-Za-z0-9_]*)+)").expect("Cannot build regexp");
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExpandError {
+    Undefined(String),
+    // 1-based column of the "${" that has no closing brace
+    Unterminated(usize),
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::Undefined(name) => write!(f, "undefined variable {}", name),
+            ExpandError::Unterminated(column) => {
+                write!(f, "unterminated \"${{\" at column {}", column)
+            }
+        }
+    }
+}
+
+impl Error for ExpandError {}
+
+// Expands $VAR, ${VAR}, ${VAR:-default} and ${VAR-default} with values from
+// lookup, $$ and \$ give a literal $. Anything else after a $ ($1, $?, $(...))
+// is left alone. Undefined variables are an error in strict mode and are
+// otherwise left as written.
+pub fn expand<F>(input: &str, lookup: F, strict: bool) -> Result<String, ExpandError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let expander = Expander {
+        lookup: &lookup,
+        strict,
+    };
+    expander.expand(input, 0)
+}
+
+// Strict mode for commands that sh expands itself. Every variable sh would
+// expand has to be defined, apart from the ones the command sets on its own.
+// Single quoted and escaped text is skipped and $$ is the shell's pid.
+pub fn check_shell<F>(input: &str, lookup: F) -> Result<(), ExpandError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let text = shell_expanded_text(input);
+    let locals = shell_locals(&text);
+    let lookup = |name: &str| lookup(name).or_else(|| locals.get(name).map(|_| String::new()));
+    expand(&text, lookup, true).map(|_| ())
+}
+
+// The command with single quoted and escaped text blanked out, keeping the
+// columns of what is left.
+fn shell_expanded_text(input: &str) -> String {
+    let blank = |c: char| " ".repeat(c.len_utf8());
+    let mut out = String::new();
+    let mut chars = input.chars();
+    let mut double_quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !double_quoted => {
+                out.push_str(&blank(c));
+                for c in chars.by_ref() {
+                    out.push_str(&blank(c));
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            '\\' => {
+                out.push_str(&blank(c));
+                out.extend(chars.next().map(blank));
+            }
+            '"' => {
+                double_quoted = !double_quoted;
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn shell_locals(text: &str) -> HashSet<String> {
+    let mut locals: HashSet<String> = ASSIGNMENT
+        .captures_iter(text)
+        .chain(FOR.captures_iter(text))
+        .map(|cap| cap[1].to_string())
+        .collect();
+    for cap in READ.captures_iter(text) {
+        locals.extend(
+            cap[1]
+                .split_whitespace()
+                .filter(|i| !i.starts_with('-'))
+                .map(String::from),
+        );
+    }
+    locals
+}
+
+struct Expander<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    strict: bool,
+}
+
+impl Expander<'_> {
+    // offset is where input starts in the original text, for error columns.
+    fn expand(&self, input: &str, offset: usize) -> Result<String, ExpandError> {
+        let mut out = String::new();
+        let mut i = 0;
+        while let Some(c) = input[i..].chars().next() {
+            if c == '\\' && input[i + 1..].starts_with('$') {
+                out.push('$');
+                i += 2;
+            } else if c == '$' {
+                let (text, length) = self.variable(&input[i..], offset + i)?;
+                out.push_str(&text);
+                i += length;
+            } else {
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+        Ok(out)
+    }
+
+    // Expands the reference input starts with, returning the text and the
+    // number of bytes it took up.
+    fn variable(&self, input: &str, offset: usize) -> Result<(String, usize), ExpandError> {
+        let after = &input[1..];
+        if after.starts_with('$') {
+            return Ok((String::from("$"), 2));
+        }
+        let body = match after.strip_prefix('{') {
+            Some(body) => body,
+            None => {
+                let length = name_length(after) + 1;
+                if length == 1 {
+                    return Ok((String::from("$"), 1));
+                }
+                let text = self.value(&after[..length - 1], &input[..length])?;
+                return Ok((text, length));
+            }
+        };
+        let end = closing_brace(body).ok_or(ExpandError::Unterminated(offset + 1))?;
+        let length = end + 3;
+        let body = &body[..end];
+        let name = &body[..name_length(body)];
+        let rest = &body[name.len()..];
+        // Where the default starts in the original text
+        let default_offset = offset + 2 + name.len();
+        let text = match rest {
+            _ if name.is_empty() => input[..length].to_string(),
+            "" => self.value(name, &input[..length])?,
+            _ if rest.starts_with(":-") => match (self.lookup)(name) {
+                Some(value) if !value.is_empty() => value,
+                _ => self.expand(&rest[2..], default_offset + 2)?,
+            },
+            _ if rest.starts_with('-') => match (self.lookup)(name) {
+                Some(value) => value,
+                None => self.expand(&rest[1..], default_offset + 1)?,
+            },
+            _ => input[..length].to_string(),
+        };
+        Ok((text, length))
+    }
+
+    fn value(&self, name: &str, text: &str) -> Result<String, ExpandError> {
+        match (self.lookup)(name) {
+            Some(value) => Ok(value),
+            None if self.strict => Err(ExpandError::Undefined(name.to_string())),
+            None => Ok(text.to_string()),
+        }
+    }
+}
+
+// Names are the longest run of [A-Za-z0-9_] not starting with a digit.
+fn name_length(s: &str) -> usize {
+    let mut length = 0;
+    for (i, c) in s.char_indices() {
+        if c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()) {
+            length = i + 1;
+        } else {
+            break;
+        }
+    }
+    length
+}
+
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup(name: &str) -> Option<String> {
+        let mut env: HashMap<&str, &str> = HashMap::new();
+        env.insert("FOO", "foo");
+        env.insert("FOOBAR", "foobar");
+        env.insert("EMPTY", "");
+        env.get(name).map(|i| i.to_string())
+    }
+
+    fn expanded(input: &str) -> String {
+        expand(input, lookup, false).unwrap()
+    }
+
+    #[test]
+    fn test_takes_the_longest_name() {
+        assert_eq!("foobar foo-bar foo_", expanded("$FOOBAR $FOO-bar ${FOO}_"));
+        assert_eq!("$FOO_", expanded("$FOO_"));
+    }
+
+    #[test]
+    fn test_braces_and_defaults() {
+        assert_eq!("foo", expanded("${FOO}"));
+        assert_eq!("foo", expanded("${FOO:-x}"));
+        assert_eq!("x", expanded("${BAR:-x}"));
+        assert_eq!("x", expanded("${EMPTY:-x}"));
+        assert_eq!("", expanded("${EMPTY-x}"));
+        assert_eq!("x", expanded("${BAR-x}"));
+        assert_eq!("foo/bin", expanded("${BAR:-$FOO/bin}"));
+        assert_eq!("foo", expanded("${BAR:-${BAZ:-$FOO}}"));
+        assert_eq!("", expanded("${BAR:-}"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!("$FOO $FOO costs $5", expanded("$$FOO \\$FOO costs $5"));
+        assert_eq!("\\n foo", expanded("\\n $FOO"));
+    }
+
+    #[test]
+    fn test_leaves_shell_syntax_alone() {
+        assert_eq!(
+            "$1 $? $(date) ${#FOO} $ end$",
+            expanded("$1 $? $(date) ${#FOO} $ end$")
+        );
+    }
+
+    #[test]
+    fn test_undefined_variables() {
+        assert_eq!("$BAR ${BAR} foo", expanded("$BAR ${BAR} $FOO"));
+        assert_eq!(
+            Err(ExpandError::Undefined("BAR".to_string())),
+            expand("$FOO $BAR", lookup, true)
+        );
+        assert_eq!(
+            Err(ExpandError::Undefined("BAZ".to_string())),
+            expand("${BAR:-$BAZ}", lookup, true)
+        );
+        assert_eq!(Ok("x".to_string()), expand("${BAR:-x}", lookup, true));
+    }
+
+    #[test]
+    fn test_unterminated_braces() {
+        let error = expand("echo ${FOO", lookup, false).unwrap_err();
+        assert_eq!(ExpandError::Unterminated(6), error);
+        assert_eq!("unterminated \"${\" at column 6", error.to_string());
+        assert_eq!(
+            Err(ExpandError::Unterminated(1)),
+            expand("${BAR:-x ${FOO}", lookup, false)
+        );
+    }
+
+    #[test]
+    fn test_keeps_non_ascii_text() {
+        assert_eq!("héllo foo ✓", expanded("héllo $FOO ✓"));
+    }
+}
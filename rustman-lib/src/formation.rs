@@ -0,0 +1,150 @@
+use crate::procfile::check_name;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub enum FormationError {
+    InvalidPair(String),
+    InvalidName(String),
+    InvalidCount(String),
+}
+
+impl fmt::Display for FormationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormationError::InvalidPair(i) => {
+                write!(f, "invalid formation entry {:?}, expected name=count", i)
+            }
+            FormationError::InvalidName(i) => write!(f, "invalid process name {:?}", i),
+            FormationError::InvalidCount(i) => write!(f, "invalid process count {:?}", i),
+        }
+    }
+}
+
+impl Error for FormationError {}
+
+// How many instances of each process type to run, as in `all=1` or `web=2,worker=3`.
+// Processes that are not named get the `all` count, or none when `all` is not given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formation {
+    default: usize,
+    counts: BTreeMap<String, usize>,
+}
+
+impl Formation {
+    pub fn count(&self, name: &str) -> usize {
+        match self.counts.get(name) {
+            Some(i) => *i,
+            None => self.default,
+        }
+    }
+}
+
+impl Default for Formation {
+    fn default() -> Formation {
+        Formation {
+            default: 1,
+            counts: BTreeMap::new(),
+        }
+    }
+}
+
+impl FromStr for Formation {
+    type Err = FormationError;
+
+    fn from_str(s: &str) -> Result<Formation, FormationError> {
+        let mut formation = Formation {
+            default: 0,
+            counts: BTreeMap::new(),
+        };
+        let formation_string: String = s.chars().filter(|i| !i.is_whitespace()).collect();
+        for pair in formation_string.split(',') {
+            let mut parts = pair.splitn(2, '=');
+            let (process, amount) = match (parts.next(), parts.next()) {
+                (Some(process), Some(amount)) => (process, amount),
+                _ => return Err(FormationError::InvalidPair(pair.to_string())),
+            };
+            check_name(process).map_err(|_| FormationError::InvalidName(process.to_string()))?;
+            let amount = amount
+                .parse::<usize>()
+                .map_err(|_| FormationError::InvalidCount(amount.to_string()))?;
+            if process == "all" {
+                formation.default = amount;
+            } else {
+                formation.counts.insert(process.to_string(), amount);
+            }
+        }
+        Ok(formation)
+    }
+}
+
+impl fmt::Display for Formation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all={}", self.default)?;
+        for (process, amount) in self.counts.iter() {
+            write!(f, ",{}={}", process, amount)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_runs_one_of_everything() {
+        let formation = Formation::default();
+        assert_eq!(1, formation.count("web"));
+        assert_eq!(1, formation.count("worker"));
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let formation: Formation = "all=2".parse().unwrap();
+        assert_eq!(2, formation.count("web"));
+    }
+
+    #[test]
+    fn test_parse_named_counts() {
+        let formation: Formation = "web=2, worker = 3".parse().unwrap();
+        assert_eq!(2, formation.count("web"));
+        assert_eq!(3, formation.count("worker"));
+        assert_eq!(0, formation.count("clock"));
+    }
+
+    #[test]
+    fn test_parse_named_counts_with_all() {
+        let formation: Formation = "all=1,web=0".parse().unwrap();
+        assert_eq!(0, formation.count("web"));
+        assert_eq!(1, formation.count("worker"));
+    }
+
+    #[test]
+    fn test_rejects_bad_formations() {
+        assert_eq!(
+            Err(FormationError::InvalidPair("web".to_string())),
+            "web".parse::<Formation>()
+        );
+        assert_eq!(
+            Err(FormationError::InvalidName("we.b".to_string())),
+            "we.b=1".parse::<Formation>()
+        );
+        assert_eq!(
+            Err(FormationError::InvalidCount("-1".to_string())),
+            "web=-1".parse::<Formation>()
+        );
+        assert_eq!(
+            Err(FormationError::InvalidCount("".to_string())),
+            "web=".parse::<Formation>()
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let formation: Formation = "worker=3,all=1,web=2".parse().unwrap();
+        assert_eq!("all=1,web=2,worker=3", formation.to_string());
+    }
+}
@@ -1,3 +1,13 @@
 pub mod engine;
+pub mod env;
+pub mod expand;
+pub mod formation;
+pub mod output;
 pub mod process;
 pub mod procfile;
+pub mod pty;
+pub mod ready;
+pub mod restart;
+pub mod shellwords;
+#[cfg(test)]
+mod tmpfile;
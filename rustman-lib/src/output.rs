@@ -0,0 +1,212 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// Where the engine sends process output, mirroring the startup/output/shutdown
+// API Foreman expects from its engine subclasses. The engine serialises calls,
+// so an implementation always sees whole lines.
+pub trait OutputSink: Send {
+    // Called once before any process is spawned, with every instance name
+    // the engine is about to run, as in "web.1".
+    fn startup(&mut self, _names: &[String]) {}
+
+    fn output(&mut self, name: &str, data: &str);
+
+    // Lines a process wrote to stderr, for processes that keep it separate.
+    fn error_output(&mut self, name: &str, data: &str) {
+        self.output(name, data)
+    }
+
+    fn shutdown(&mut self) {}
+}
+
+impl fmt::Debug for dyn OutputSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutputSink")
+    }
+}
+
+// Writes `HH:MM:SS name | data` lines to stdout with process names padded to
+// a common width. Stderr lines are red when stdout is a terminal, unless
+// NO_COLOR is set.
+#[derive(Debug)]
+pub struct TerminalSink {
+    padding: usize,
+    color: bool,
+}
+
+impl TerminalSink {
+    pub fn new() -> TerminalSink {
+        let terminal = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        TerminalSink {
+            padding: "system".len(),
+            color: terminal && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    fn write(&self, name: &str, data: &str, stderr: bool) {
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        for message in data.lines() {
+            // Nowhere to report a broken stdout, the line is dropped.
+            let _ = handle.write_all(self.render(name, message, stderr).as_bytes());
+        }
+        let _ = handle.flush();
+    }
+
+    fn render(&self, name: &str, message: &str, stderr: bool) -> String {
+        match stderr && self.color {
+            true => self.format(name, &format!("\x1b[31m{}\x1b[0m", message)),
+            false => self.format(name, message),
+        }
+    }
+
+    fn format(&self, name: &str, message: &str) -> String {
+        format!(
+            "{} {:width$} | {}\n",
+            timestamp(),
+            name,
+            message,
+            width = self.padding
+        )
+    }
+}
+
+impl Default for TerminalSink {
+    fn default() -> TerminalSink {
+        TerminalSink::new()
+    }
+}
+
+impl OutputSink for TerminalSink {
+    fn startup(&mut self, names: &[String]) {
+        self.padding = names.iter().map(|i| i.len()).fold(self.padding, usize::max);
+    }
+
+    fn output(&mut self, name: &str, data: &str) {
+        self.write(name, data, false);
+    }
+
+    fn error_output(&mut self, name: &str, data: &str) {
+        self.write(name, data, true);
+    }
+}
+
+// Collects output as `name: data` lines, and stderr kept separate as
+// `name (stderr): data`, for tests and tools that want to inspect it
+// afterwards. Clones share the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct BufferSink {
+    buffer: Arc<Mutex<String>>,
+}
+
+impl BufferSink {
+    pub fn new() -> BufferSink {
+        BufferSink::default()
+    }
+
+    pub fn contents(&self) -> String {
+        self.buffer.lock().unwrap().clone()
+    }
+}
+
+impl OutputSink for BufferSink {
+    fn startup(&mut self, _names: &[String]) {
+        self.buffer.lock().unwrap().clear();
+    }
+
+    fn output(&mut self, name: &str, data: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for message in data.lines() {
+            buffer.push_str(&format!("{}: {}\n", name, message));
+        }
+    }
+
+    fn error_output(&mut self, name: &str, data: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for message in data.lines() {
+            buffer.push_str(&format!("{} (stderr): {}\n", name, message));
+        }
+    }
+}
+
+fn timestamp() -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn test_format_pads_the_name() {
+        let mut sink = TerminalSink::new();
+        sink.startup(&["web.1".to_string(), "worker.1".to_string()]);
+        let line = sink.format("web.1", "listening");
+        let re = Regex::new(r"^\d\d:\d\d:\d\d web\.1    \| listening\n$").unwrap();
+        assert!(re.is_match(&line), "{:?}", line);
+    }
+
+    #[test]
+    fn test_padding_is_at_least_system() {
+        let mut sink = TerminalSink::new();
+        sink.startup(&["a.1".to_string()]);
+        assert!(sink.format("a.1", "x").ends_with(" a.1    | x\n"));
+        assert!(sink.format("system", "x").ends_with(" system | x\n"));
+    }
+
+    #[test]
+    fn test_format_does_not_truncate_long_names() {
+        let sink = TerminalSink::new();
+        let line = sink.format("worker.10", "busy");
+        assert!(line.ends_with(" worker.10 | busy\n"), "{:?}", line);
+    }
+
+    #[test]
+    fn test_stderr_is_coloured_on_a_terminal() {
+        let mut sink = TerminalSink::new();
+        sink.color = true;
+        assert!(sink
+            .render("web.1", "oops", true)
+            .ends_with(" | \x1b[31moops\x1b[0m\n"));
+        assert!(sink.render("web.1", "fine", false).ends_with(" | fine\n"));
+        sink.color = false;
+        assert!(sink.render("web.1", "oops", true).ends_with(" | oops\n"));
+    }
+
+    #[test]
+    fn test_buffer_sink_collects_lines() {
+        let sink = BufferSink::new();
+        let mut writer = sink.clone();
+        writer.startup(&[]);
+        writer.output("web.1", "one\ntwo\n");
+        writer.error_output("web.1", "oops\n");
+        writer.output("system", "done");
+        writer.shutdown();
+        assert_eq!(
+            "web.1: one\nweb.1: two\nweb.1 (stderr): oops\nsystem: done\n",
+            sink.contents()
+        );
+    }
+
+    struct Recorder(Vec<String>);
+
+    impl OutputSink for Recorder {
+        fn output(&mut self, name: &str, data: &str) {
+            self.0.push(format!("{} {}", name, data));
+        }
+    }
+
+    #[test]
+    fn test_error_output_defaults_to_output() {
+        let mut sink = Recorder(Vec::new());
+        sink.error_output("web.1", "oops");
+        assert_eq!(vec!["web.1 oops"], sink.0);
+    }
+}
@@ -1,15 +1,60 @@
+use crate::expand::{check_shell, expand, ExpandError};
+use crate::pty;
+use crate::ready::Probe;
+use crate::shellwords::{self, ShellwordsError};
 use std::collections::HashMap;
 use std::env;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
 use std::str;
+use std::str::FromStr;
+
+// Whether a child's stderr shares the stdout pipe, keeping the order the two
+// were written in, or is read as a stream of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StderrMode {
+    #[default]
+    Merge,
+    Separate,
+}
+
+impl FromStr for StderrMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StderrMode, String> {
+        match s {
+            "merge" => Ok(StderrMode::Merge),
+            "separate" => Ok(StderrMode::Separate),
+            _ => Err(format!(
+                "invalid stderr mode {:?}, expected merge or separate",
+                s
+            )),
+        }
+    }
+}
+
+// What a process run to completion by Process::exec wrote and how it ended.
+#[derive(Debug)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ExitStatus,
+}
 
 #[derive(Debug)]
 pub struct Process {
     command: String,
     cwd: Option<String>,
+    #[allow(dead_code)]
     output: Option<String>,
     env: HashMap<String, String>,
+    stderr: StderrMode,
+    pty: bool,
+    after: Vec<String>,
+    ready: Option<Probe>,
 }
 
 impl Process {
@@ -23,97 +68,238 @@ impl Process {
             command,
             cwd,
             output,
-            env: match env {
-                Some(i) => i,
-                None => HashMap::new(),
-            },
+            env: env.unwrap_or_default(),
+            stderr: StderrMode::default(),
+            pty: false,
+            after: Vec::new(),
+            ready: None,
         }
     }
 
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn stderr(&self) -> StderrMode {
+        self.stderr
+    }
+
+    pub fn set_stderr(&mut self, stderr: StderrMode) {
+        self.stderr = stderr;
+    }
+
+    pub fn pty(&self) -> bool {
+        self.pty
+    }
+
+    // Runs the process on a pseudo-terminal, for tools that only colour or
+    // line buffer their output on a TTY. Stdout and stderr both go to the
+    // terminal, whose master is handed back as the child's stdout.
+    pub fn set_pty(&mut self, pty: bool) {
+        self.pty = pty;
+    }
+
+    pub fn after(&self) -> &[String] {
+        &self.after
+    }
+
+    // The processes that have to be ready before this one starts.
+    pub fn set_after(&mut self, after: Vec<String>) {
+        self.after = after;
+    }
+
+    pub fn ready(&self) -> Option<&Probe> {
+        self.ready.as_ref()
+    }
+
+    // Without a probe an instance is ready as soon as it is started.
+    pub fn set_ready(&mut self, ready: Option<Probe>) {
+        self.ready = ready;
+    }
+
+    // Commands that do not expand cleanly are returned as written. $$ and \$
+    // give a literal $ here and in exec: commands only, sh commands run as
+    // written, so there $$ is the shell's pid.
     pub fn expanded_command(&self, custom_env: Option<&HashMap<String, String>>) -> String {
-        let mut out_expanded_command = self.command.clone();
-        let mut env = self.env.clone();
-        if let Some(i) = custom_env {
-            for (key, val) in i.iter() {
-                env.insert(key.to_string(), val.to_string());
-            }
-        }
-        for (key, val) in env.iter() {
-            out_expanded_command = out_expanded_command.replace(&format!("${}", key), val);
+        self.expand_command(custom_env, false)
+            .unwrap_or_else(|_| self.command.clone())
+    }
+
+    // Variables come from the per-run options first, then the process env,
+    // then the environment rustman itself was started with.
+    pub fn expand_command(
+        &self,
+        custom_env: Option<&HashMap<String, String>>,
+        strict: bool,
+    ) -> Result<String, ExpandError> {
+        expand(&self.command, |name| self.lookup(custom_env, name), strict)
+    }
+
+    fn lookup(&self, custom_env: Option<&HashMap<String, String>>, name: &str) -> Option<String> {
+        custom_env
+            .and_then(|i| i.get(name))
+            .or_else(|| self.env.get(name))
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    // What --strict-env checks before anything is started, exec: commands
+    // as they are split and sh commands as sh expands them.
+    pub fn check_command(
+        &self,
+        custom_env: Option<&HashMap<String, String>>,
+    ) -> Result<(), ExpandError> {
+        let lookup = |name: &str| self.lookup(custom_env, name);
+        match self.direct_command() {
+            Some(line) => match shellwords::split_with(line, |text| expand(text, lookup, true)) {
+                Err(ShellwordsError::Expand(e)) => Err(e),
+                // Other mistakes are reported when the command is run
+                _ => Ok(()),
+            },
+            None => check_shell(&self.command, lookup),
         }
-        out_expanded_command
     }
 
-    pub fn run(&self, options: Option<HashMap<String, String>>) -> Child {
+    pub fn run(&self, options: Option<HashMap<String, String>>) -> io::Result<Child> {
+        self.run_in(&self.cwd(), options)
+    }
+
+    // Runs in the given directory instead of the process's own.
+    pub fn run_in(&self, cwd: &str, options: Option<HashMap<String, String>>) -> io::Result<Child> {
         let mut env = self.env.clone();
         if let Some(i) = options {
             env.extend(i)
         };
-        let path = env::current_dir().unwrap();
-        self.chdir(self.cwd());
-        let cmd = self.expanded_command(Some(&env));
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .envs(&env)
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("failed to execute process");
-        self.chdir(path.into_os_string().into_string().unwrap());
-        output
-    }
-
-    pub fn exec(&mut self, options: Option<HashMap<String, String>>) -> String {
+        let mut command = self.build_command(cwd, &env)?;
+        command.envs(&env);
+        // Every child leads a process group of its own, so the engine can
+        // signal everything it started. A pty child gets one from setsid.
+        if self.pty {
+            let (master, slave) = pty::open()?;
+            command
+                .stdin(slave.try_clone()?)
+                .stdout(slave.try_clone()?)
+                .stderr(slave);
+            unsafe {
+                command.pre_exec(pty::make_controlling_terminal);
+            }
+            let mut child = command.spawn()?;
+            drop(command);
+            child.stdout = Some(ChildStdout::from(master));
+            return Ok(child);
+        }
+        // A background group reading the terminal would be stopped by SIGTTIN
+        command.process_group(0).stdin(Stdio::null());
+        match self.stderr {
+            StderrMode::Separate => command
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn(),
+            // Both streams write to one pipe, handed back as the child's stdout.
+            StderrMode::Merge => {
+                let (reader, writer) = io::pipe()?;
+                command.stdout(writer.try_clone()?).stderr(writer);
+                let mut child = command.spawn()?;
+                // Drop the write ends, or the reader would never see EOF
+                drop(command);
+                child.stdout = Some(ChildStdout::from(OwnedFd::from(reader)));
+                Ok(child)
+            }
+        }
+    }
+
+    // The merged environment is only given to the child, the supervisor's own
+    // environment is left alone.
+    pub fn exec(&self, options: Option<HashMap<String, String>>) -> io::Result<ExecOutput> {
         let mut env = self.env.clone();
         if let Some(i) = options {
             env.extend(i)
         };
-        for (key, val) in env.iter() {
-            env::set_var(key, val);
-        }
-        self.chdir(self.cwd());
-        let cmd = self.expanded_command(Some(&self.env));
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .expect("failed to execute process");
-        str::from_utf8(&output.stdout).unwrap().to_string()
+        let output = self.build_command(&self.cwd(), &env)?.envs(&env).output()?;
+        Ok(ExecOutput {
+            stdout: utf8(output.stdout, "stdout")?,
+            stderr: utf8(output.stderr, "stderr")?,
+            status: output.status,
+        })
     }
 
-    fn chdir(&self, in_cwd: String) {
-        assert!(env::set_current_dir(&in_cwd).is_ok());
-        println!("Successfully changed working directory to {}!", in_cwd);
+    // Commands starting with "exec:" are split into words and run without a
+    // shell, so the pid, signals and exit status are the service's own.
+    pub fn direct_command(&self) -> Option<&str> {
+        self.command.strip_prefix("exec:").map(|i| i.trim_start())
     }
 
-    pub fn cwd(&self) -> String {
-        let env_cwd = match self.env.get("cwd") {
-            Some(i) => i,
-            None => ".",
+    // The working directory is only ever set on the child, so concurrent
+    // spawns do not race on the supervisor's own directory.
+    fn build_command(&self, cwd: &str, env: &HashMap<String, String>) -> io::Result<Command> {
+        if !Path::new(cwd).is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("working directory {} does not exist", cwd),
+            ));
+        }
+        let mut command = match self.direct_command() {
+            Some(line) => {
+                let lookup = |name: &str| self.lookup(Some(env), name);
+                let words = shellwords::split_with(line, |text| expand(text, lookup, false))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let (program, args) = words.split_first().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "exec: needs a command")
+                })?;
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+            // sh expands the variables itself from the child's environment,
+            // which holds the same values lookup finds, so a value is never
+            // parsed as shell code.
+            None => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(&self.command);
+                command
+            }
         };
-        Path::new(env_cwd)
-            .canonicalize()
-            .unwrap()
-            .into_os_string()
-            .into_string()
-            .unwrap()
+        command.current_dir(cwd);
+        Ok(command)
     }
+
+    // Relative to the supervisor's directory, the engine resolves it against
+    // its root when the process spawns.
+    pub fn cwd(&self) -> String {
+        match &self.cwd {
+            Some(i) => i.to_string(),
+            None => String::from("."),
+        }
+    }
+}
+
+fn utf8(bytes: Vec<u8>, stream: &str) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid UTF-8: {}", stream, e.utf8_error()),
+        )
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::error::Error;
+    use std::env;
+    use std::fs;
     use std::io::prelude::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::thread;
+    use std::time::Duration;
 
-    static TEST_BIN: &'static str = "tests/test.sh";
-    static ENV_BIN: &'static str = "tests/env.sh";
-    static ECHO_BIN: &'static str = "tests/echo.sh";
-    static UTF8_BIN: &'static str = "tests/utf8.sh";
+    static TEST_BIN: &str = "tests/test.sh";
+    static ENV_BIN: &str = "tests/env.sh";
+    static ECHO_BIN: &str = "tests/echo.sh";
+    static UTF8_BIN: &str = "tests/utf8.sh";
 
     fn run(process: Process, options: Option<HashMap<String, String>>) -> String {
-        let mut child = process.run(options);
-        let output = child.wait().unwrap();
+        let mut child = process.run(options).unwrap();
+        child.wait().unwrap();
         let mut s = String::new();
         match child.stdout.unwrap().read_to_string(&mut s) {
             Err(e) => panic!("couldn't read wc stdout: {}", e),
@@ -123,15 +309,21 @@ mod tests {
 
     #[test]
     fn test_cwd() {
-        //TODO
-    }
-    #[test]
-    fn test_chdir() {
-        //TODO
+        assert_eq!(".", Process::new("pwd".to_string(), None, None, None).cwd());
+        assert_eq!("tests", in_dir("tests").cwd());
     }
     #[test]
     fn test_exec() {
-        //TODO
+        let process = Process::new(
+            "echo out; echo err >&2; exit 3".to_string(),
+            None,
+            None,
+            None,
+        );
+        let output = process.exec(None).unwrap();
+        assert_eq!("out\n", output.stdout);
+        assert_eq!("err\n", output.stderr);
+        assert_eq!(Some(3), output.status.code());
     }
     #[test]
     fn test_run() {
@@ -139,7 +331,247 @@ mod tests {
     }
     #[test]
     fn test_expanded_command() {
-        //TODO
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("FOO".to_string(), "foo".to_string());
+        env.insert("FOOBAR".to_string(), "foobar".to_string());
+        let process = Process::new(
+            "run $FOOBAR ${FOO}bar ${MISSING:-none} $$FOO ${FOO".to_string(),
+            None,
+            None,
+            Some(env),
+        );
+        assert_eq!(
+            "run $FOOBAR ${FOO}bar ${MISSING:-none} $$FOO ${FOO",
+            process.expanded_command(None)
+        );
+        let process = Process::new(
+            "run $FOOBAR ${FOO}bar ${MISSING:-none} $$FOO".to_string(),
+            None,
+            None,
+            Some(process.env.clone()),
+        );
+        assert_eq!(
+            "run foobar foobar none $FOO",
+            process.expanded_command(None)
+        );
+    }
+
+    #[test]
+    fn test_expansion_precedence() {
+        let ambient = env::var("PATH").unwrap();
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("HOME".to_string(), "process".to_string());
+        env.insert("USER".to_string(), "process".to_string());
+        let process = Process::new("$PATH $HOME $USER".to_string(), None, None, Some(env));
+        let mut options: HashMap<String, String> = HashMap::new();
+        options.insert("USER".to_string(), "options".to_string());
+        assert_eq!(
+            format!("{} process options", ambient),
+            process.expanded_command(Some(&options))
+        );
+    }
+
+    #[test]
+    fn test_strict_expansion() {
+        let process = Process::new("echo $RUSTMAN_UNDEFINED".to_string(), None, None, None);
+        assert_eq!(
+            Err(ExpandError::Undefined("RUSTMAN_UNDEFINED".to_string())),
+            process.expand_command(None, true)
+        );
+        assert_eq!("echo $RUSTMAN_UNDEFINED", process.expanded_command(None));
+    }
+
+    #[test]
+    fn test_direct_exec_splits_like_a_shell() {
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("FOO".to_string(), "foo bar".to_string());
+        let process = Process::new(
+            "exec: printf '%s|' \"a b\" 'c $FOO' $FOO \\$FOO".to_string(),
+            None,
+            None,
+            Some(env),
+        );
+        assert_eq!(
+            Some("printf '%s|' \"a b\" 'c $FOO' $FOO \\$FOO"),
+            process.direct_command()
+        );
+        assert_eq!("a b|c $FOO|foo bar|$FOO|", run(process, None));
+    }
+
+    #[test]
+    fn test_direct_exec_runs_without_a_shell() {
+        let process = Process::new("exec: sleep 10".to_string(), None, None, None);
+        let mut child = process.run(None).unwrap();
+        // The child may not have reached exec yet
+        let proc = Path::new("/proc").join(child.id().to_string());
+        let mut comm = String::new();
+        for _ in 0..100 {
+            comm = fs::read_to_string(proc.join("comm")).unwrap();
+            if comm == "sleep\n" {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stat = fs::read_to_string(proc.join("stat")).unwrap();
+        let cmdline = fs::read_to_string(proc.join("cmdline")).unwrap();
+        child.kill().unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!("sleep\n", comm);
+        assert_eq!("sleep\u{0}10\u{0}", cmdline);
+        // Started by the test itself, not by a shell in between
+        let ppid = stat.rsplit(") ").next().unwrap().split(' ').nth(1).unwrap();
+        assert_eq!(std::process::id().to_string(), ppid);
+        assert_eq!(Some(libc::SIGKILL), status.signal());
+
+        let process = Process::new("exec: tests/env.sh".to_string(), None, None, None);
+        let output = process.exec(None).unwrap();
+        assert_eq!("\n", output.stdout);
+        let process = Process::new("exec: false".to_string(), None, None, None);
+        assert_eq!(Some(1), process.exec(None).unwrap().status.code());
+    }
+
+    #[test]
+    fn test_direct_exec_errors() {
+        let process = Process::new("exec: cat log | grep x".to_string(), None, None, None);
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            process.run(None).unwrap_err().kind()
+        );
+        let process = Process::new("exec:  ".to_string(), None, None, None);
+        assert_eq!(
+            "exec: needs a command",
+            process.run(None).unwrap_err().to_string()
+        );
+        let process = Process::new("exec: tests/missing.sh".to_string(), None, None, None);
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            process.run(None).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_stderr_modes() {
+        let command = "echo out; echo err >&2; echo more".to_string();
+        let process = Process::new(command.clone(), None, None, None);
+        assert_eq!(StderrMode::Merge, process.stderr());
+        let child = process.run(None).unwrap();
+        assert!(child.stderr.is_none());
+        let output = child.wait_with_output().unwrap();
+        assert_eq!("out\nerr\nmore\n", String::from_utf8_lossy(&output.stdout));
+
+        let mut process = Process::new(command, None, None, None);
+        process.set_stderr(StderrMode::Separate);
+        let output = process.run(None).unwrap().wait_with_output().unwrap();
+        assert_eq!("out\nmore\n", String::from_utf8_lossy(&output.stdout));
+        assert_eq!("err\n", String::from_utf8_lossy(&output.stderr));
+    }
+
+    #[test]
+    fn test_runs_on_a_pty() {
+        let command = "test -t 1 && echo stdout is a tty; test -t 2 && echo stderr is too >&2; \
+                       test -t 0 && echo stdin is too";
+        let mut process = Process::new(command.to_string(), None, None, None);
+        process.set_pty(true);
+        let mut child = process.run(None).unwrap();
+        child.wait().unwrap();
+        // The master reports EIO once the terminal is closed
+        let mut output = Vec::new();
+        let _ = child.stdout.take().unwrap().read_to_end(&mut output);
+        assert_eq!(
+            "stdout is a tty\r\nstderr is too\r\nstdin is too\r\n",
+            String::from_utf8_lossy(&output)
+        );
+
+        let process = Process::new(command.to_string(), None, None, None);
+        assert_eq!("", run(process, None));
+    }
+
+    #[test]
+    fn test_leads_its_own_process_group() {
+        let process = Process::new("sleep 10".to_string(), None, None, None);
+        let mut child = process.run(None).unwrap();
+        let pgid = unsafe { libc::getpgid(child.id() as libc::pid_t) };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.id() as libc::pid_t, pgid);
+    }
+
+    #[test]
+    fn test_stdin_is_empty() {
+        let process = Process::new("read x || echo eof".to_string(), None, None, None);
+        assert_eq!("eof\n", run(process, None));
+    }
+
+    #[test]
+    fn test_parse_stderr_mode() {
+        assert_eq!(Ok(StderrMode::Merge), "merge".parse());
+        assert_eq!(Ok(StderrMode::Separate), "separate".parse());
+        assert!("both".parse::<StderrMode>().is_err());
+    }
+
+    #[test]
+    fn test_sh_commands_run_as_written() {
+        let process = Process::new("echo \\$HOME '$HOME' '${'".to_string(), None, None, None);
+        assert_eq!("$HOME $HOME ${\n", run(process, None));
+        let process = Process::new("echo $$".to_string(), None, None, None);
+        let mut child = process.run(None).unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        child.wait().unwrap();
+        assert_eq!(format!("{}\n", child.id()), output);
+    }
+
+    #[test]
+    fn test_check_command() {
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("FOO".to_string(), "foo".to_string());
+        let check = |command: &str| {
+            Process::new(command.to_string(), None, None, Some(env.clone())).check_command(None)
+        };
+        let undefined = |name: &str| Err(ExpandError::Undefined(name.to_string()));
+        assert_eq!(
+            Ok(()),
+            check("echo $FOO '$RUSTMAN_UNDEFINED' \\$RUSTMAN_UNDEFINED $$")
+        );
+        assert_eq!(Ok(()), check("echo \"it's $FOO\" '${'"));
+        assert_eq!(Ok(()), check("for f in *; do echo $f; done"));
+        assert_eq!(Ok(()), check("X=1; read -r line; echo $X $line"));
+        assert_eq!(Ok(()), check("echo ${RUSTMAN_UNDEFINED:-none}"));
+        assert_eq!(
+            undefined("RUSTMAN_UNDEFINED"),
+            check("echo \"$RUSTMAN_UNDEFINED\"")
+        );
+        assert_eq!(
+            undefined("RUSTMAN_UNDEFINED"),
+            check("echo 'a' $RUSTMAN_UNDEFINED")
+        );
+        assert_eq!(Ok(()), check("exec: echo '$RUSTMAN_UNDEFINED' $FOO"));
+        assert_eq!(
+            undefined("RUSTMAN_UNDEFINED"),
+            check("exec: echo $RUSTMAN_UNDEFINED")
+        );
+    }
+
+    #[test]
+    fn test_values_are_not_parsed_by_the_shell() {
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("PASSWORD".to_string(), "abc$HOME".to_string());
+        env.insert("GREETING".to_string(), "hi; echo INJECTED".to_string());
+        let process = Process::new(
+            "echo pw=$PASSWORD; echo \"$GREETING\" ${GREETING}".to_string(),
+            None,
+            None,
+            Some(env),
+        );
+        assert_eq!(
+            "pw=abc$HOME\nhi; echo INJECTED hi; echo INJECTED\n",
+            run(process, None)
+        );
     }
 
     #[test]
@@ -158,8 +590,6 @@ mod tests {
 
     #[test]
     fn test_can_handle_env_vars_in_the_command() {
-        let hello = String::from("Olá");
-
         let mut env: HashMap<String, String> = HashMap::new();
         env.insert("FOO".to_string(), "bar".to_string());
         let process = Process::new(format!("{} $FOO", ECHO_BIN), None, None, Some(env));
@@ -174,12 +604,14 @@ mod tests {
         assert_eq!("bar\n", run(process, Some(env)));
     }
 
-    //TODO
-    //#[test]
-    //fn test_should_output_utf8_properly() {
-    //    let process = Process::new(UTF8_BIN.to_string(), None, None, None);
-    //    assert_eq!(str::from_utf8(b"\\xE2").unwrap(), run(process, None));
-    //}
+    // Foreman passes the raw bytes through, a lone \xE2 is an error here.
+    #[test]
+    fn test_should_output_utf8_properly() {
+        let process = Process::new(UTF8_BIN.to_string(), None, None, None);
+        let error = process.exec(None).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().starts_with("stdout is not valid UTF-8"));
+    }
 
     #[test]
     fn test_can_expand_env_in_the_command() {
@@ -201,15 +633,61 @@ mod tests {
 
     #[test]
     fn test_can_execute() {
-        let mut process = Process::new(TEST_BIN.to_string(), None, None, None);
-        assert_eq!("testing\n", process.exec(None));
+        let process = Process::new(TEST_BIN.to_string(), None, None, None);
+        assert_eq!("testing\n", process.exec(None).unwrap().stdout);
     }
 
     #[test]
     fn test_can_execute_with_env() {
         let mut env: HashMap<String, String> = HashMap::new();
         env.insert("FOO".to_string(), "bar".to_string());
-        let mut process = Process::new(format!("{} FOO", ENV_BIN), None, None, None);
-        assert_eq!("bar\n", process.exec(Some(env)));
+        let process = Process::new(format!("{} FOO", ENV_BIN), None, None, None);
+        assert_eq!("bar\n", process.exec(Some(env)).unwrap().stdout);
+    }
+
+    #[test]
+    fn test_exec_does_not_leak_env_into_the_parent() {
+        let mut env: HashMap<String, String> = HashMap::new();
+        env.insert("RUSTMAN_EXEC_ONLY".to_string(), "child".to_string());
+        let process = Process::new("echo $RUSTMAN_EXEC_ONLY".to_string(), None, None, None);
+        assert_eq!("child\n", process.exec(Some(env)).unwrap().stdout);
+        assert!(env::var("RUSTMAN_EXEC_ONLY").is_err());
+    }
+
+    fn in_dir(dir: &str) -> Process {
+        Process::new("pwd".to_string(), Some(dir.to_string()), None, None)
+    }
+
+    #[test]
+    fn test_runs_in_its_own_directory() {
+        let before = env::current_dir().unwrap();
+        let expected = Path::new("tests").canonicalize().unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                thread::spawn(move || match i % 2 {
+                    0 => run(in_dir("tests"), None),
+                    _ => run(in_dir("."), None),
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let output = thread.join().unwrap();
+            match i % 2 {
+                0 => assert_eq!(format!("{}\n", expected.display()), output),
+                _ => assert_eq!(format!("{}\n", before.display()), output),
+            }
+        }
+        assert_eq!(before, env::current_dir().unwrap());
+    }
+
+    #[test]
+    fn test_missing_directory_is_an_error() {
+        let error = in_dir("tests/missing").run(None).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        assert_eq!(
+            "working directory tests/missing does not exist",
+            error.to_string()
+        );
+        assert!(in_dir("tests/missing").exec(None).is_err());
     }
 }
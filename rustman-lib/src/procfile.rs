@@ -1,15 +1,133 @@
+use crate::process::StderrMode;
+use crate::ready::{self, Probe};
+use crate::restart::RestartPolicy;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::BTreeMap;
+use std::error::Error;
 use std::fmt;
-#[cfg(test)]
 use std::fs::File;
-#[cfg(test)]
+use std::io;
 use std::io::prelude::*;
 use std::ops::Index;
+use std::str::FromStr;
 
 lazy_static! {
     static ref RE: Regex = Regex::new(r"^([A-Za-z0-9_-]+):\s*(.+)$").expect("Cannot build regexp");
+    static ref NAME: Regex = Regex::new(r"^[A-Za-z0-9_-]+$").expect("Cannot build regexp");
+    static ref ANNOTATION: Regex =
+        Regex::new(r"^\s*#@\s*([A-Za-z0-9_-]+)\s*:\s*(.*?)\s*$").expect("Cannot build regexp");
+}
+
+// Keys understood in `#@ key: value` annotations. Annotations are comments
+// placed directly above an entry, so other tools read them as comments.
+const ANNOTATIONS: [&str; 6] = ["stderr", "pty", "restart", "after", "ready", "cwd"];
+
+// The one rule for process names, shared by entries, formations, restart
+// policies and after annotations.
+pub(crate) fn check_name(name: &str) -> Result<(), String> {
+    match NAME.is_match(name) {
+        true => Ok(()),
+        false => Err(format!("invalid process name {:?}", name)),
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcfileError {
+    Io {
+        filename: String,
+        source: io::Error,
+    },
+    MissingFilename,
+    // Line and column are 1-based.
+    Malformed {
+        line: usize,
+        column: usize,
+        reason: String,
+        text: String,
+    },
+    Duplicate {
+        name: String,
+        line: usize,
+        first_line: usize,
+    },
+    InvalidName(String),
+    InvalidCommand(String),
+    NoSuchProcess(String),
+    ProcessExists(String),
+}
+
+impl fmt::Display for ProcfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcfileError::Io { filename, source } => write!(f, "{}: {}", filename, source),
+            ProcfileError::MissingFilename => write!(f, "no Procfile filename given"),
+            ProcfileError::Malformed {
+                line,
+                column,
+                reason,
+                text,
+            } => write!(
+                f,
+                "line {}, column {}: {}: {:?}",
+                line, column, reason, text
+            ),
+            ProcfileError::Duplicate {
+                name,
+                line,
+                first_line,
+            } => write!(
+                f,
+                "line {}: process {:?} is already defined on line {}",
+                line, name, first_line
+            ),
+            ProcfileError::InvalidName(name) => write!(f, "invalid process name {:?}", name),
+            ProcfileError::InvalidCommand(command) => write!(f, "invalid command {:?}", command),
+            ProcfileError::NoSuchProcess(name) => write!(f, "no such process {:?}", name),
+            ProcfileError::ProcessExists(name) => {
+                write!(f, "process {:?} is already defined", name)
+            }
+        }
+    }
+}
+
+impl Error for ProcfileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProcfileError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// An Error is something Procfile::new rejects, a Warning is accepted but
+// probably not what was meant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.severity, self.message)
+    }
 }
 
 #[derive(Debug)]
@@ -17,6 +135,7 @@ pub struct Entry {
     line: String,
     name: String,
     command: String,
+    options: BTreeMap<String, String>,
 }
 
 impl Entry {
@@ -25,8 +144,61 @@ impl Entry {
             line,
             name,
             command,
+            options: BTreeMap::new(),
         }
     }
+
+    // The value of a `#@ key: value` annotation above the entry.
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(|i| i.as_str())
+    }
+
+    pub fn options(&self) -> &BTreeMap<String, String> {
+        &self.options
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    // The line as written in the Procfile, including any line ending other than '\n'.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    // Edits keep the separator and line ending the entry was written with.
+    fn set_command(&mut self, command: &str) {
+        let line = self.line.strip_suffix('\r').unwrap_or(&self.line);
+        let prefix = &line[..line.len() - self.command.len()];
+        let ending = &self.line[line.len()..];
+        self.line = format!("{}{}{}", prefix, command, ending);
+        self.command = command.to_string();
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.line = format!("{}{}", name, &self.line[self.name.len()..]);
+        self.name = name.to_string();
+    }
+}
+
+// Replaces a name in the list of an `after:` annotation, keeping the spacing
+// around each name.
+fn rename_in_after(value: &str, name: &str, new_name: &str) -> String {
+    let names: Vec<String> = value
+        .split(',')
+        .map(|i| {
+            if i.trim() == name {
+                i.replacen(name, new_name, 1)
+            } else {
+                i.to_string()
+            }
+        })
+        .collect();
+    names.join(",")
 }
 
 impl fmt::Display for Entry {
@@ -35,154 +207,549 @@ impl fmt::Display for Entry {
     }
 }
 
+// Every line of the file is kept as it was read, so a Procfile that is
+// loaded and saved again comes back byte for byte.
+#[derive(Debug)]
+enum Line {
+    Entry(Entry),
+    // Comments and blank lines
+    Other(String),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Entry(entry) => f.write_str(&entry.line),
+            Line::Other(text) => f.write_str(text),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Procfile {
-    entries: BTreeMap<String, Entry>,
+    lines: Vec<Line>,
+    // Whether save ends the file in a newline that the lines do not have,
+    // which it does once an entry is appended as the last line.
+    newline: bool,
 }
 
 impl Index<String> for Procfile {
     type Output = Entry;
     fn index(&self, i: String) -> &Entry {
-        &self.entries[&i]
+        self.get(&i).expect("no such process")
     }
 }
 
 impl fmt::Display for Procfile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let newline = "\n";
-        let mut count = 0;
-
-        for (_entry_name, entry) in self.entries.iter() {
-            if count > 0 {
-                f.write_str(newline)?
-            } else {
-                count += 1;
+        for (index, line) in self.lines.iter().enumerate() {
+            if index > 0 {
+                f.write_str("\n")?;
             }
-            f.write_str(&entry.to_string())?;
+            write!(f, "{}", line)?;
         }
         Ok(())
     }
 }
 
 impl<'a> Procfile {
-    #[cfg(test)]
-    fn load(&mut self, filename: Option<&str>) {
-        self.entries.clear();
-        self.parse(filename);
+    pub fn load(&mut self, filename: Option<&str>) -> Result<(), ProcfileError> {
+        self.lines.clear();
+        self.newline = false;
+        self.parse(filename)
     }
 
-    fn guard_filename(&self, filename: Option<&'a str>) -> &'a str {
-        match filename {
-            Some(i) => i,
+    fn guard_filename(&self, filename: Option<&'a str>) -> Result<&'a str, ProcfileError> {
+        filename.ok_or(ProcfileError::MissingFilename)
+    }
+
+    // Adds the process, or replaces its command in place when it already exists.
+    pub fn set(&mut self, name: &str, command: &str) -> Result<(), ProcfileError> {
+        check_name(name).map_err(|_| ProcfileError::InvalidName(name.to_string()))?;
+        if command.trim().is_empty() || command.contains('\n') || command.contains('\r') {
+            return Err(ProcfileError::InvalidCommand(command.to_string()));
+        }
+        let command = command.trim();
+        if let Some(entry) = self.entry_mut(name) {
+            entry.set_command(command);
+            return Ok(());
+        }
+        let entry = Entry::new(
+            format!("{}: {}", name, command),
+            name.to_string(),
+            command.to_string(),
+        );
+        // Goes before the trailing newline of a loaded file, and gets one
+        // when saved otherwise. A file that did not end in a newline is left
+        // that way until something is appended.
+        match self.lines.last() {
+            Some(Line::Other(text)) if text.is_empty() => {
+                self.lines.insert(self.lines.len() - 1, Line::Entry(entry))
+            }
             _ => {
-                panic!("Cannot parse when filename is None");
+                self.lines.push(Line::Entry(entry));
+                self.newline = true;
             }
         }
+        Ok(())
     }
 
-    #[cfg(test)]
-    fn delete(&mut self, name: &str) {
-        self.entries.remove(name);
-    }
-
-    #[cfg(test)]
-    fn save(&self, filename: Option<&str>) -> std::io::Result<()> {
-        let file_to_save = self.guard_filename(filename);
-        let mut file = File::create(file_to_save)?;
-        file.write_all(self.to_string().as_bytes())?;
-        file.sync_all()?;
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), ProcfileError> {
+        check_name(new_name).map_err(|_| ProcfileError::InvalidName(new_name.to_string()))?;
+        if name != new_name && self.get(new_name).is_some() {
+            return Err(ProcfileError::ProcessExists(new_name.to_string()));
+        }
+        match self.entry_mut(name) {
+            Some(entry) => entry.set_name(new_name),
+            None => return Err(ProcfileError::NoSuchProcess(name.to_string())),
+        }
+        // Processes that start after this one keep pointing at it.
+        for line in self.lines.iter_mut() {
+            match line {
+                Line::Entry(entry) => {
+                    if let Some(value) = entry.options.get_mut("after") {
+                        *value = rename_in_after(value, name, new_name);
+                    }
+                }
+                Line::Other(text) => {
+                    let range = match ANNOTATION.captures(text) {
+                        Some(cap) if &cap[1] == "after" => cap.get(2).map(|i| i.range()),
+                        _ => None,
+                    };
+                    if let Some(range) = range {
+                        let value = rename_in_after(&text[range.clone()], name, new_name);
+                        text.replace_range(range, &value);
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    fn parse(&mut self, filename: Option<&str>) {
-        let file_to_parse = self.guard_filename(filename);
-        let data = match std::fs::read_to_string(file_to_parse) {
-            Ok(string) => string,
-            Err(error) => {
-                panic!("Error {:?}", error);
+    // The `#@` annotations above the entry go with it, or they would attach
+    // to the entry below. Other comments are left alone.
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        let index = self
+            .lines
+            .iter()
+            .position(|line| matches!(line, Line::Entry(entry) if entry.name == name))?;
+        let comment =
+            |line: &Line| matches!(line, Line::Other(text) if text.trim().starts_with('#'));
+        let start = self.lines[..index]
+            .iter()
+            .rposition(|line| !comment(line))
+            .map_or(0, |i| i + 1);
+        let entry = match self.lines.remove(index) {
+            Line::Entry(entry) => entry,
+            Line::Other(_) => return None,
+        };
+        for i in (start..index).rev() {
+            if matches!(&self.lines[i], Line::Other(text) if ANNOTATION.is_match(text)) {
+                self.lines.remove(i);
             }
+        }
+        Some(entry)
+    }
+
+    // Written to a temporary file next to the target and renamed over it, so
+    // readers never see a half written Procfile.
+    pub fn save(&self, filename: Option<&str>) -> Result<(), ProcfileError> {
+        let file_to_save = self.guard_filename(filename)?;
+        let io_error = |source| ProcfileError::Io {
+            filename: file_to_save.to_string(),
+            source,
         };
+        let mut contents = self.to_string();
+        if self.newline && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        let tmp_filename = format!("{}.{}.tmp", file_to_save, std::process::id());
+        let result = File::create(&tmp_filename)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp_filename, file_to_save));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_filename);
+        }
+        result.map_err(io_error)
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Option<&mut Entry> {
+        self.lines.iter_mut().find_map(|line| match line {
+            Line::Entry(entry) if entry.name == name => Some(entry),
+            _ => None,
+        })
+    }
+
+    fn parse(&mut self, filename: Option<&str>) -> Result<(), ProcfileError> {
+        let file_to_parse = self.guard_filename(filename)?;
+        let data = std::fs::read_to_string(file_to_parse).map_err(|source| ProcfileError::Io {
+            filename: file_to_parse.to_string(),
+            source,
+        })?;
+        self.parse_str(&data)
+    }
+
+    fn parse_str(&mut self, data: &str) -> Result<(), ProcfileError> {
+        let mut lines: BTreeMap<String, usize> = BTreeMap::new();
+        let mut options = BTreeMap::new();
+        for (index, raw) in data.split('\n').enumerate() {
+            let line = raw.strip_suffix('\r').unwrap_or(raw);
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                options.clear();
+            }
+            if let Some(cap) = ANNOTATION.captures(line) {
+                if let Err(reason) = check_option(&cap[1], &cap[2]) {
+                    return Err(ProcfileError::Malformed {
+                        line: index + 1,
+                        column: cap.get(2).unwrap().start() + 1,
+                        reason,
+                        text: line.to_string(),
+                    });
+                }
+                options.insert(cap[1].to_string(), cap[2].to_string());
+            }
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                self.lines.push(Line::Other(raw.to_string()));
+                continue;
+            }
+            let cap = match RE.captures(line) {
+                Some(cap) => cap,
+                None => {
+                    let (column, reason) = diagnose(line);
+                    return Err(ProcfileError::Malformed {
+                        line: index + 1,
+                        column,
+                        reason,
+                        text: line.to_string(),
+                    });
+                }
+            };
+            let mut entry = Entry::new(raw.to_string(), cap[1].to_string(), cap[2].to_string());
+            entry.options = std::mem::take(&mut options);
+            if let Some(first_line) = lines.get(&entry.name) {
+                return Err(ProcfileError::Duplicate {
+                    name: entry.name,
+                    line: index + 1,
+                    first_line: *first_line,
+                });
+            }
+            lines.insert(entry.name.clone(), index + 1);
+            self.lines.push(Line::Entry(entry));
+        }
+        Ok(())
+    }
+
+    // Entries in the order they appear in the file.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            lines: self.lines.iter(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.iter().find(|entry| entry.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.iter().map(|entry| entry.name()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // Report every problem in a Procfile instead of stopping at the first one.
+    pub fn validate(filename: &str) -> Result<Vec<Diagnostic>, ProcfileError> {
+        let data = std::fs::read_to_string(filename).map_err(|source| ProcfileError::Io {
+            filename: filename.to_string(),
+            source,
+        })?;
+        Ok(Procfile::validate_str(&data))
+    }
 
-        for line in data.replace("\r\n", "\n").split('\n') {
-            for cap in RE.captures_iter(line) {
-                let entry = Entry::new(
-                    line.to_string(),
-                    (&cap[1]).to_string(),
-                    (&cap[2]).to_string(),
+    fn validate_str(data: &str) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut lines: BTreeMap<String, usize> = BTreeMap::new();
+        // Annotations waiting for the entry below them
+        let mut annotations = Vec::new();
+        // Every process with the line and value of its after annotation
+        let mut after: Option<(usize, String)> = None;
+        let mut dependencies: Vec<(String, Option<(usize, String)>)> = Vec::new();
+        // The line of a `stderr: separate` annotation, and whether `pty: true`
+        // goes with it, which sends both streams to the terminal anyway
+        let mut separate = None;
+        let mut on_pty = false;
+        let unattached = |line| Diagnostic {
+            line,
+            severity: Severity::Warning,
+            message: String::from("annotation is not attached to a process"),
+        };
+        for (index, line) in data.replace("\r\n", "\n").split('\n').enumerate() {
+            let trimmed = line.trim();
+            let mut diagnostic = |severity, message| {
+                diagnostics.push(Diagnostic {
+                    line: index + 1,
+                    severity,
+                    message,
+                })
+            };
+            if trimmed.is_empty() {
+                diagnostics.extend(annotations.drain(..).map(unattached));
+                after = None;
+                separate = None;
+                on_pty = false;
+                continue;
+            }
+            if let Some(cap) = ANNOTATION.captures(line) {
+                if !ANNOTATIONS.contains(&&cap[1]) {
+                    diagnostic(
+                        Severity::Warning,
+                        format!("unknown annotation {:?}", &cap[1]),
+                    );
+                } else if let Err(reason) = check_option(&cap[1], &cap[2]) {
+                    diagnostic(Severity::Error, reason);
+                } else if &cap[1] == "after" {
+                    after = Some((index + 1, cap[2].to_string()));
+                } else if &cap[1] == "stderr" {
+                    separate = Some(index + 1).filter(|_| &cap[2] == "separate");
+                } else if &cap[1] == "pty" {
+                    on_pty = &cap[2] == "true";
+                }
+                annotations.push(index + 1);
+            }
+            if trimmed.starts_with('#') {
+                continue;
+            }
+            annotations.clear();
+            let entry_after = after.take();
+            let separate_on_pty = separate.take().filter(|_| on_pty);
+            on_pty = false;
+            let cap = match RE.captures(line) {
+                Some(cap) => cap,
+                None => {
+                    let (column, reason) = diagnose(line);
+                    diagnostic(
+                        Severity::Error,
+                        format!("column {}: {}: {:?}", column, reason, line),
+                    );
+                    continue;
+                }
+            };
+            if cap[2].trim().is_empty() {
+                diagnostic(
+                    Severity::Warning,
+                    format!("process {:?} has an empty command", &cap[1]),
                 );
-                self.entries.insert(entry.name.clone(), entry);
             }
+            match lines.get(&cap[1]) {
+                Some(first_line) => diagnostic(
+                    Severity::Error,
+                    format!(
+                        "process {:?} is already defined on line {}",
+                        &cap[1], first_line
+                    ),
+                ),
+                None => {
+                    lines.insert(cap[1].to_string(), index + 1);
+                    dependencies.push((cap[1].to_string(), entry_after));
+                }
+            }
+            if let Some(line) = separate_on_pty {
+                diagnostics.push(Diagnostic {
+                    line,
+                    severity: Severity::Warning,
+                    message: String::from(
+                        "stderr: separate has no effect with pty: true, both go to the terminal",
+                    ),
+                });
+            }
+        }
+        diagnostics.extend(annotations.drain(..).map(unattached));
+        diagnostics.extend(Procfile::validate_dependencies(&dependencies));
+        diagnostics.sort_by_key(|i| i.line);
+        diagnostics
+    }
+
+    // Dependencies can only be checked once every process is known.
+    fn validate_dependencies(
+        dependencies: &[(String, Option<(usize, String)>)],
+    ) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let position = |name: &str| dependencies.iter().position(|(i, _)| i == name);
+        let mut after = Vec::new();
+        for (_, annotation) in dependencies.iter() {
+            let mut indexes = Vec::new();
+            if let Some((line, value)) = annotation {
+                for name in ready::parse_after(value).unwrap_or_default() {
+                    match position(&name) {
+                        Some(index) => indexes.push(index),
+                        None => diagnostics.push(Diagnostic {
+                            line: *line,
+                            severity: Severity::Error,
+                            message: format!("process {:?} in after is not defined", name),
+                        }),
+                    }
+                }
+            }
+            after.push(indexes);
+        }
+        if let Err(cycle) = ready::start_order(&after) {
+            let names: Vec<&str> = cycle.iter().map(|i| dependencies[*i].0.as_str()).collect();
+            diagnostics.push(Diagnostic {
+                line: dependencies[cycle[0]]
+                    .1
+                    .as_ref()
+                    .map(|(line, _)| *line)
+                    .unwrap(),
+                severity: Severity::Error,
+                message: format!("dependency cycle: {}", names.join(" -> ")),
+            });
         }
+        diagnostics
     }
 
-    pub fn new(filename: Option<&str>) -> Procfile {
+    pub fn new(filename: Option<&str>) -> Result<Procfile, ProcfileError> {
         let mut procfile = Procfile {
-            entries: BTreeMap::new(),
+            lines: Vec::new(),
+            newline: false,
         };
-        match filename {
-            Some(_) => {
-                procfile.parse(filename);
-                procfile
-            }
-            None => procfile,
+        if filename.is_some() {
+            procfile.parse(filename)?;
         }
+        Ok(procfile)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::Rng;
-    use std::fs::File;
-    use std::path::Path;
+impl FromStr for Procfile {
+    type Err = ProcfileError;
 
-    static PROCFILE_IN_PATH: &'static str = "tests/Procfile";
-    static PROCFILE_OUT_PATH: &'static str = "tests/Procfile.out";
-    static PROCFILE_WRITE_PROCFILE: &'static str = "tests/Procfile.tmp";
+    fn from_str(s: &str) -> Result<Procfile, ProcfileError> {
+        let mut procfile = Procfile::new(None)?;
+        procfile.parse_str(s)?;
+        Ok(procfile)
+    }
+}
 
-    struct TmpFile {
-        filename: String,
+#[derive(Debug)]
+pub struct Iter<'a> {
+    lines: std::slice::Iter<'a, Line>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Entry;
+
+    fn next(&mut self) -> Option<&'a Entry> {
+        self.lines.find_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Other(_) => None,
+        })
     }
+}
 
-    impl Drop for TmpFile {
-        fn drop(&mut self) {
-            TmpFile::delete_file(self.filename.as_str());
-        }
+#[derive(Debug)]
+pub struct IntoIter {
+    lines: std::vec::IntoIter<Line>,
+}
+
+impl Iterator for IntoIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        self.lines.find_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Other(_) => None,
+        })
     }
+}
 
-    impl TmpFile {
-        fn delete_file(filename: &str) {
-            if Path::new(filename).exists() {
-                std::fs::remove_file(filename);
-            }
-        }
+impl<'a> IntoIterator for &'a Procfile {
+    type Item = &'a Entry;
+    type IntoIter = Iter<'a>;
 
-        fn write_procfile(procfile: Option<&str>, alpha_env: Option<&str>) -> TmpFile {
-            let mut rng = rand::thread_rng();
-            let random_number = rng.gen_range(0, 1000000);
-            let filename = match procfile {
-                Some(i) => i,
-                None => PROCFILE_WRITE_PROCFILE,
-            };
-            let final_filename = format!("{}.{}", filename, random_number);
-            let mut file =
-                File::create(final_filename.as_str()).expect("write_procfile failed creating file");
-            let alpha = match alpha_env {
-                Some(i) => i,
-                None => "",
-            };
-            file.write_all(format!("alpha: ./alpha{}\n", alpha).as_bytes());
-            file.write_all(format!("bravo:\t./bravo\n").as_bytes());
-            file.write_all(format!("foo_bar:\t./foo_bar\n").as_bytes());
-            file.write_all(format!("foo-bar:\t./foo-bar\n").as_bytes());
-            file.write_all(format!("# baz:\t./baz\n").as_bytes());
-            file.sync_all();
-            let tmpfile = TmpFile {
-                filename: final_filename.to_string(),
-            };
-            tmpfile
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for Procfile {
+    type Item = Entry;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            lines: self.lines.into_iter(),
         }
     }
+}
+
+// Unknown keys are accepted, so newer Procfiles still load.
+fn check_option(key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "stderr" => value.parse::<StderrMode>().map(|_| ()),
+        "restart" => value.parse::<RestartPolicy>().map(|_| ()),
+        "after" => ready::parse_after(value).map(|_| ()),
+        "ready" => value.parse::<Probe>().map(|_| ()),
+        "cwd" if value.is_empty() => Err(String::from("cwd needs a directory")),
+        "pty" if value != "true" && value != "false" => Err(format!(
+            "invalid pty value {:?}, expected true or false",
+            value
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Where and why a line that is not a comment fails to be a `name: command` entry.
+fn diagnose(line: &str) -> (usize, String) {
+    let name_length = line
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .count();
+    match line[name_length..].chars().next() {
+        _ if name_length == 0 => (1, String::from("expected a process name")),
+        Some(':') => (
+            name_length + 2,
+            String::from("expected a command after ':'"),
+        ),
+        Some(c) if !c.is_whitespace() => (
+            name_length + 1,
+            format!("invalid character {:?} in process name", c),
+        ),
+        _ => (
+            name_length + 1,
+            String::from("expected ':' after process name"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmpfile::TmpFile;
+    use std::path::Path;
+
+    static PROCFILE_IN_PATH: &str = "tests/Procfile";
+    static PROCFILE_OUT_PATH: &str = "tests/Procfile.out";
+    static PROCFILE_WRITE_PROCFILE: &str = "tests/Procfile.tmp";
+    static PROCFILE_BAD_PATH: &str = "tests/Procfile.bad";
+    static PROCFILE_MALFORMED_PATH: &str = "tests/Procfile.malformed";
+
+    fn write_procfile(procfile: Option<&str>, alpha_env: Option<&str>) -> TmpFile {
+        let filename = procfile.unwrap_or(PROCFILE_WRITE_PROCFILE);
+        let alpha = alpha_env.unwrap_or_default();
+        let contents = format!(
+            "alpha: ./alpha{}\nbravo:\t./bravo\nfoo_bar:\t./foo_bar\nfoo-bar:\t./foo-bar\n# baz:\t./baz\n",
+            alpha
+        );
+        TmpFile::write(filename, &contents)
+    }
 
     #[test]
     fn test_entry_constructor() {
@@ -198,9 +765,8 @@ mod tests {
 
     #[test]
     fn test_regexp_creation() {
-        match Regex::new(RE.as_str()) {
-            Result::Err(_) => panic!("Failed building regexp"),
-            _ => {}
+        if Regex::new(RE.as_str()).is_err() {
+            panic!("Failed building regexp");
         }
     }
 
@@ -208,11 +774,7 @@ mod tests {
     fn test_procfile_entry() {
         let line = String::from("web: rails server");
         for cap in RE.captures_iter(&line) {
-            let entry = Entry::new(
-                line.to_string(),
-                (&cap[1]).to_string(),
-                (&cap[2]).to_string(),
-            );
+            let entry = Entry::new(line.to_string(), cap[1].to_string(), cap[2].to_string());
             assert_eq!(entry.line, line);
             assert_eq!(entry.name, String::from("web"));
             assert_eq!(entry.command, String::from("rails server"));
@@ -224,11 +786,7 @@ mod tests {
         let line = String::from("web: ");
         assert_eq!(1, RE.captures_iter(&line).count());
         for cap in RE.captures_iter(&line) {
-            let entry = Entry::new(
-                line.to_string(),
-                (&cap[1]).to_string(),
-                (&cap[2]).to_string(),
-            );
+            let entry = Entry::new(line.to_string(), cap[1].to_string(), cap[2].to_string());
             assert_eq!(entry.line, line);
             assert_eq!(entry.name, String::from("web"));
         }
@@ -238,49 +796,172 @@ mod tests {
     fn test_parse() {
         let procfile1 = std::fs::read_to_string(PROCFILE_IN_PATH)
             .expect("test_parse failed reading procfile1_path");
-        let procfile = Procfile::new(Some(PROCFILE_IN_PATH));
-        assert_eq!(procfile.to_string(), procfile1.trim().to_string());
+        let procfile = Procfile::new(Some(PROCFILE_IN_PATH)).unwrap();
+        assert_eq!(procfile.to_string(), procfile1);
     }
 
     #[test]
     fn test_procfile_save() {
-        TmpFile::delete_file(PROCFILE_OUT_PATH);
-
+        let out = TmpFile::write(PROCFILE_OUT_PATH, "");
         let procfile1 = std::fs::read_to_string(PROCFILE_IN_PATH)
             .expect("test_procfile_save failed reading procfile1_path");
-        let procfile = Procfile::new(Some(PROCFILE_IN_PATH));
-        assert_eq!(procfile.to_string(), procfile1.trim().to_string());
+        let procfile = Procfile::new(Some(PROCFILE_IN_PATH)).unwrap();
+        assert_eq!(procfile.to_string(), procfile1);
 
-        procfile.save(Some(PROCFILE_OUT_PATH)).expect("Save failed");
+        procfile.save(Some(&out.filename)).expect("Save failed");
 
-        let procfile2 = std::fs::read_to_string(PROCFILE_OUT_PATH)
+        let procfile2 = std::fs::read_to_string(&out.filename)
             .expect("test_procfile_save failed reading procfile2_path");
-        let procfile = Procfile::new(Some(PROCFILE_OUT_PATH));
-        assert_eq!(procfile.to_string(), procfile2.trim().to_string());
+        assert_eq!(procfile1, procfile2);
+    }
 
-        TmpFile::delete_file(PROCFILE_OUT_PATH);
+    #[test]
+    fn test_remove() {
+        let mut procfile = Procfile::new(Some(PROCFILE_IN_PATH)).unwrap();
+        assert_eq!(5, procfile.len());
+        assert_eq!("bin/env FOO", procfile.remove("env").unwrap().command);
+        assert!(procfile.remove("env").is_none());
+        assert_eq!(4, procfile.len());
+        assert!(!procfile.to_string().contains("env:"));
     }
 
     #[test]
-    fn test_delete() {
-        let mut procfile = Procfile::new(Some(PROCFILE_IN_PATH));
-        assert_eq!(5, procfile.entries.len());
-        procfile.delete(&procfile.entries.keys().next().unwrap().clone());
-        assert_eq!(4, procfile.entries.len());
+    fn test_remove_takes_the_annotations_along() {
+        let data = "# web\n#@ pty: true\n#@ restart: always\nweb: x\nworker: y\n";
+        let mut procfile: Procfile = data.parse().unwrap();
+        procfile.remove("web").unwrap();
+        assert_eq!("# web\nworker: y\n", procfile.to_string());
+        let procfile: Procfile = procfile.to_string().parse().unwrap();
+        assert!(procfile.get("worker").unwrap().options().is_empty());
     }
+
+    #[test]
+    fn test_round_trip_keeps_comments_order_and_whitespace() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let contents = std::fs::read_to_string(&tmpfile.filename).unwrap();
+        let procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        assert_eq!(contents, procfile.to_string());
+        assert!(procfile.to_string().contains("# baz:\t./baz\n"));
+
+        let contents = "# web\r\nweb:  rails s\r\n\r\nworker: rake\r\n";
+        let crlf = TmpFile::write(&format!("{}.crlf", PROCFILE_WRITE_PROCFILE), contents);
+        let procfile = Procfile::new(Some(crlf.filename.as_str())).unwrap();
+        assert_eq!(contents, procfile.to_string());
+        assert_eq!("rails s", procfile["web".to_string()].command);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        match Procfile::new(Some("tests/Procfile.missing")) {
+            Err(ProcfileError::Io { filename, source }) => {
+                assert_eq!("tests/Procfile.missing", filename);
+                assert_eq!(io::ErrorKind::NotFound, source.kind());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_filename() {
+        let mut procfile = Procfile::new(None).unwrap();
+        assert!(matches!(
+            procfile.load(None),
+            Err(ProcfileError::MissingFilename)
+        ));
+        assert!(matches!(
+            procfile.save(None),
+            Err(ProcfileError::MissingFilename)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_line() {
+        let error = Procfile::new(Some(PROCFILE_MALFORMED_PATH)).unwrap_err();
+        assert_eq!(
+            "line 2, column 7: expected ':' after process name: \"worker rake jobs:work\"",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_diagnose() {
+        assert_eq!(
+            (1, String::from("expected a process name")),
+            diagnose(" web: x")
+        );
+        assert_eq!(
+            (5, String::from("expected a command after ':'")),
+            diagnose("web:")
+        );
+        assert_eq!(
+            (3, String::from("invalid character '.' in process name")),
+            diagnose("we.b: x")
+        );
+        assert_eq!(
+            (4, String::from("expected ':' after process name")),
+            diagnose("web")
+        );
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), Some("\nbravo: ./again"));
+        match Procfile::new(Some(tmpfile.filename.as_str())) {
+            Err(ProcfileError::Duplicate {
+                name,
+                line,
+                first_line,
+            }) => {
+                assert_eq!("bravo", name);
+                assert_eq!(3, line);
+                assert_eq!(2, first_line);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate_reports_every_finding() {
+        let data = "web: rails s\nweb rails s\nworker: \n# ok: comment\nwe.b: x\nweb: puma\n";
+        let diagnostics = Procfile::validate_str(data);
+        let report: Vec<String> = diagnostics.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            vec![
+                "line 2: error: column 4: expected ':' after process name: \"web rails s\"",
+                "line 3: warning: process \"worker\" has an empty command",
+                "line 5: error: column 3: invalid character '.' in process name: \"we.b: x\"",
+                "line 6: error: process \"web\" is already defined on line 1",
+            ],
+            report
+        );
+    }
+
+    #[test]
+    fn test_validate_clean_procfile() {
+        assert!(Procfile::validate(PROCFILE_IN_PATH).unwrap().is_empty());
+        assert!(Procfile::validate("tests/Procfile.missing").is_err());
+    }
+
+    // Procfile.bad is valid, its "bad" process is the one that fails when run.
+    #[test]
+    fn test_bad_procfile_parses() {
+        let procfile = Procfile::new(Some(PROCFILE_BAD_PATH)).unwrap();
+        assert_eq!("false", procfile["bad".to_string()].command);
+    }
+
     // Tests from https://github.com/ddollar/foreman/blob/master/spec/foreman/procfile_spec.rb
     #[test]
     fn test_can_load_from_a_file() {
-        let tmpfile = TmpFile::write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
-        let mut procfile = Procfile::new(None);
-        procfile.load(Some(tmpfile.filename.as_str()));
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let mut procfile = Procfile::new(None).unwrap();
+        procfile.load(Some(tmpfile.filename.as_str())).unwrap();
         assert_eq!("./alpha", procfile["alpha".to_string()].command);
         assert_eq!("./bravo", procfile["bravo".to_string()].command);
     }
     #[test]
     fn test_loads_a_passed_in_procfile() {
-        let tmpfile = TmpFile::write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
-        let procfile = Procfile::new(Some(tmpfile.filename.as_str()));
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
         assert_eq!("./alpha", procfile["alpha".to_string()].command);
         assert_eq!("./bravo", procfile["bravo".to_string()].command);
         assert_eq!("./foo-bar", procfile["foo-bar".to_string()].command);
@@ -288,46 +969,272 @@ mod tests {
     }
     #[test]
     fn test_it_only_creates_procfile_entries_for_lines_matching_regex() {
-        let tmpfile = TmpFile::write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
-        let procfile = Procfile::new(Some(tmpfile.filename.as_str()));
-        let ref_keys = vec!["alpha", "bravo", "foo-bar", "foo_bar"];
-        let test_keys: Vec<&str> = procfile.entries.keys().map(|i| i.as_str()).collect();
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        let ref_keys = vec!["alpha", "bravo", "foo_bar", "foo-bar"];
+        let test_keys: Vec<&str> = procfile.names();
         assert_eq!(ref_keys, test_keys);
     }
 
     #[test]
     fn test_returns_nil_when_attempting_to_retrieve_an_non_existing_entry() {
-        let tmpfile = TmpFile::write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
-        let procfile = Procfile::new(Some(tmpfile.filename.as_str()));
-        assert!(!procfile.entries.contains_key("unicorn"));
-    }
-
-    // Need to implement IndexMut
-
-    //#[test]
-    //fn test_can_have_a_process_appended_to_it() {
-    //    let tmpfile = TmpFile::write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
-    //    let procfile = Procfile::new(Some(tmpfile.filename.as_str()));
-    //    //subject["charlie"] = "./charlie"
-    //    //expect(subject["charlie"]).to eq("./charlie")
-    //}
-
-    //#[test]
-    //fn test_can_write_to_a_string() {
-    //    //it "can write to a string" do
-    //    //subject["foo"] = "./foo"
-    //    //subject["bar"] = "./bar"
-    //    //expect(subject.to_s).to eq("foo: ./foo\nbar: ./bar")
-    //    //end
-    //}
-
-    //#[test]
-    //fn test_can_write_to_a_file() {
-    //    // subject["foo"] = "./foo"
-    //    // subject["bar"] = "./bar"
-    //    // Dir.mkdir('/tmp')
-    //    // subject.save "/tmp/proc"
-    //    // expect(File.read("/tmp/proc")).to eq("foo: ./foo\nbar: ./bar\n")
-    //    // end
-    //}
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        assert!(procfile.get("unicorn").is_none());
+    }
+
+    #[test]
+    fn test_can_have_a_process_appended_to_it() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let mut procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        procfile.set("charlie", "./charlie").unwrap();
+        assert_eq!("./charlie", procfile["charlie".to_string()].command);
+        assert!(procfile
+            .to_string()
+            .ends_with("# baz:\t./baz\ncharlie: ./charlie\n"));
+    }
+
+    #[test]
+    fn test_can_write_to_a_string() {
+        let mut procfile = Procfile::new(None).unwrap();
+        procfile.set("foo", "./foo").unwrap();
+        procfile.set("bar", "./bar").unwrap();
+        assert_eq!("foo: ./foo\nbar: ./bar", procfile.to_string());
+    }
+
+    #[test]
+    fn test_can_write_to_a_file() {
+        let tmpfile = TmpFile::write(&format!("{}.write", PROCFILE_WRITE_PROCFILE), "");
+        let mut procfile = Procfile::new(None).unwrap();
+        procfile.set("foo", "./foo").unwrap();
+        procfile.set("bar", "./bar").unwrap();
+        procfile.save(Some(tmpfile.filename.as_str())).unwrap();
+        assert_eq!(
+            "foo: ./foo\nbar: ./bar\n",
+            std::fs::read_to_string(&tmpfile.filename).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_replaces_in_place() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let mut procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        procfile.set("bravo", "./bravo --fast").unwrap();
+        assert!(procfile.to_string().contains("\nbravo:\t./bravo --fast\n"));
+        let names: Vec<&str> = procfile.names();
+        assert_eq!(vec!["alpha", "bravo", "foo_bar", "foo-bar"], names);
+    }
+
+    #[test]
+    fn test_set_rejects_bad_entries() {
+        let mut procfile = Procfile::new(None).unwrap();
+        assert!(matches!(
+            procfile.set("we.b", "x"),
+            Err(ProcfileError::InvalidName(_))
+        ));
+        assert!(matches!(
+            procfile.set("web", " "),
+            Err(ProcfileError::InvalidCommand(_))
+        ));
+        assert!(matches!(
+            procfile.set("web", "a\nb"),
+            Err(ProcfileError::InvalidCommand(_))
+        ));
+    }
+
+    #[test]
+    fn test_rename() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let mut procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        procfile.rename("bravo", "charlie").unwrap();
+        assert!(procfile.to_string().contains("\ncharlie:\t./bravo\n"));
+        assert!(matches!(
+            procfile.rename("alpha", "charlie"),
+            Err(ProcfileError::ProcessExists(_))
+        ));
+        assert!(matches!(
+            procfile.rename("bravo", "delta"),
+            Err(ProcfileError::NoSuchProcess(_))
+        ));
+    }
+
+    #[test]
+    fn test_rename_updates_after_annotations() {
+        let data = "#@ after: db\nweb: a\n#@after:cache ,db\nworker: b\ndb: c\n";
+        let mut procfile: Procfile = data.parse().unwrap();
+        procfile.rename("db", "postgres").unwrap();
+        assert_eq!(
+            "#@ after: postgres\nweb: a\n#@after:cache ,postgres\nworker: b\npostgres: c\n",
+            procfile.to_string()
+        );
+        assert_eq!(
+            Some("postgres"),
+            procfile.get("web").unwrap().option("after")
+        );
+        assert_eq!(
+            Some("cache ,postgres"),
+            procfile.get("worker").unwrap().option("after")
+        );
+    }
+
+    #[test]
+    fn test_save_keeps_a_missing_final_newline() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        std::fs::write(&tmpfile.filename, "web: a\nworker: b").unwrap();
+        let mut procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        procfile.set("web", "a").unwrap();
+        procfile.save(Some(tmpfile.filename.as_str())).unwrap();
+        let contents = std::fs::read_to_string(&tmpfile.filename).unwrap();
+        assert_eq!("web: a\nworker: b", contents);
+        procfile.set("clock", "c").unwrap();
+        procfile.save(Some(tmpfile.filename.as_str())).unwrap();
+        let contents = std::fs::read_to_string(&tmpfile.filename).unwrap();
+        assert_eq!("web: a\nworker: b\nclock: c\n", contents);
+    }
+
+    #[test]
+    fn test_save_replaces_the_file() {
+        let tmpfile = write_procfile(Some(PROCFILE_WRITE_PROCFILE), None);
+        let mut procfile = Procfile::new(Some(tmpfile.filename.as_str())).unwrap();
+        procfile.remove("alpha");
+        procfile.save(Some(tmpfile.filename.as_str())).unwrap();
+        let contents = std::fs::read_to_string(&tmpfile.filename).unwrap();
+        assert!(contents.starts_with("bravo:\t./bravo\n"));
+        let tmp_filename = format!("{}.{}.tmp", tmpfile.filename, std::process::id());
+        assert!(!Path::new(&tmp_filename).exists());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let procfile: Procfile = "# comment\nweb: rails s\r\nworker:  rake\n"
+            .parse()
+            .unwrap();
+        assert_eq!(2, procfile.len());
+        assert!(!procfile.is_empty());
+        assert_eq!(vec!["web", "worker"], procfile.names());
+        let web = procfile.get("web").unwrap();
+        assert_eq!("web", web.name());
+        assert_eq!("rails s", web.command());
+        assert_eq!("web: rails s\r", web.line());
+        assert_eq!("rake", procfile.get("worker").unwrap().command());
+        assert!(procfile.get("clock").is_none());
+        assert!(Procfile::new(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_iteration_is_in_file_order() {
+        let procfile: Procfile = "b: ./b\n\na: ./a\n".parse().unwrap();
+        let commands: Vec<&str> = procfile.iter().map(|i| i.command()).collect();
+        assert_eq!(vec!["./b", "./a"], commands);
+        let mut names = Vec::new();
+        for entry in &procfile {
+            names.push(entry.name().to_string());
+        }
+        assert_eq!(vec!["b", "a"], names);
+        let entries: Vec<Entry> = procfile.into_iter().collect();
+        assert_eq!("./a", entries[1].command());
+    }
+
+    #[test]
+    fn test_from_str_reports_errors() {
+        let error = "web: x\nweb x\n".parse::<Procfile>().unwrap_err();
+        assert_eq!(
+            "line 2, column 4: expected ':' after process name: \"web x\"",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_annotations() {
+        let data = "#@ stderr: separate\n# a comment\n#@ future:  yes \nweb: rails s\n\n#@ stderr: merge\n\nworker: rake\n";
+        let procfile: Procfile = data.parse().unwrap();
+        let web = procfile.get("web").unwrap();
+        assert_eq!(Some("separate"), web.option("stderr"));
+        assert_eq!(Some("yes"), web.option("future"));
+        assert!(procfile.get("worker").unwrap().options().is_empty());
+        assert_eq!(data, procfile.to_string());
+    }
+
+    #[test]
+    fn test_invalid_annotation() {
+        let error = "#@ stderr: both\nweb: x\n".parse::<Procfile>().unwrap_err();
+        assert_eq!(
+            "line 1, column 12: invalid stderr mode \"both\", expected merge or separate: \"#@ stderr: both\"",
+            error.to_string()
+        );
+        let error = "#@ pty: yes\nweb: x\n".parse::<Procfile>().unwrap_err();
+        assert_eq!(
+            "line 1, column 9: invalid pty value \"yes\", expected true or false: \"#@ pty: yes\"",
+            error.to_string()
+        );
+        let error = "#@ restart: often\nweb: x\n"
+            .parse::<Procfile>()
+            .unwrap_err();
+        assert_eq!(
+            "line 1, column 13: invalid restart policy \"often\", expected never, on-failure or always: \"#@ restart: often\"",
+            error.to_string()
+        );
+        let error = "#@ cwd:\nweb: x\n".parse::<Procfile>().unwrap_err();
+        assert_eq!(
+            "line 1, column 8: cwd needs a directory: \"#@ cwd:\"",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_validate_annotations() {
+        let data = "#@ stderr: both\n#@ colour: red\nweb: x\n#@ stderr: merge\n\n#@ stderr: merge";
+        let report: Vec<String> = Procfile::validate_str(data)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "line 1: error: invalid stderr mode \"both\", expected merge or separate",
+                "line 2: warning: unknown annotation \"colour\"",
+                "line 4: warning: annotation is not attached to a process",
+                "line 6: warning: annotation is not attached to a process",
+            ],
+            report
+        );
+    }
+
+    #[test]
+    fn test_validate_separate_stderr_on_a_pty() {
+        let data = "#@ stderr: separate\n#@ pty: true\nweb: x\n#@ stderr: separate\nworker: y\n";
+        let report: Vec<String> = Procfile::validate_str(data)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            vec!["line 1: warning: stderr: separate has no effect with pty: true, both go to the terminal"],
+            report
+        );
+    }
+
+    #[test]
+    fn test_validate_dependencies() {
+        let data = "#@ after: worker\nweb: x\n#@ after: web, db\nworker: y\n#@ ready: tcp:5432\nclock: z\n#@ after: clock\n\nredis: w\n";
+        let report: Vec<String> = Procfile::validate_str(data)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "line 1: error: dependency cycle: web -> worker -> web",
+                "line 3: error: process \"db\" in after is not defined",
+                "line 7: warning: annotation is not attached to a process",
+            ],
+            report
+        );
+        let data = "#@ after: db\nweb: x\n#@ ready: log:ready\ndb: y\n";
+        assert!(Procfile::validate_str(data).is_empty());
+        let error = "#@ ready: http:80\nweb: x\n"
+            .parse::<Procfile>()
+            .unwrap_err();
+        assert_eq!(
+            "line 1, column 11: invalid ready probe \"http:80\", expected tcp:, file:, command: or log:: \"#@ ready: http:80\"",
+            error.to_string()
+        );
+    }
 }
@@ -0,0 +1,86 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+
+// Opens a pseudo-terminal pair, returning the master and the slave. The
+// slave gets the size of the terminal rustman runs in, if it has one.
+pub fn open() -> io::Result<(OwnedFd, File)> {
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if master < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { OwnedFd::from_raw_fd(master) };
+    let mut name = [0 as libc::c_char; 128];
+    unsafe {
+        if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let error = libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len());
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(name.to_string_lossy().as_ref())?;
+    if let Some(size) = window_size() {
+        set_window_size(&master, &size)?;
+    }
+    Ok((master, slave))
+}
+
+// The size of the terminal rustman runs in, if any of its standard streams
+// is one.
+pub fn window_size() -> Option<libc::winsize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0 {
+            return Some(size);
+        }
+    }
+    None
+}
+
+// The kernel sends SIGWINCH to the foreground process group of the terminal.
+pub fn set_window_size<F: AsRawFd>(fd: &F, size: &libc::winsize) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Run in the child between fork and exec, once the slave is its stdout: a
+// new session with the slave as controlling terminal, as a login would have.
+pub fn make_controlling_terminal() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() < 0 || libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_size_reaches_the_slave() {
+        let (master, slave) = open().unwrap();
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        size.ws_row = 24;
+        size.ws_col = 80;
+        set_window_size(&master, &size).unwrap();
+        let mut read: libc::winsize = unsafe { std::mem::zeroed() };
+        assert_eq!(0, unsafe {
+            libc::ioctl(slave.as_raw_fd(), libc::TIOCGWINSZ, &mut read)
+        });
+        assert_eq!((24, 80), (read.ws_row, read.ws_col));
+        assert_eq!(1, unsafe { libc::isatty(slave.as_raw_fd()) });
+    }
+}
@@ -0,0 +1,202 @@
+use crate::procfile::check_name;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+// How the engine tells that an instance is ready for the processes that
+// start after it, as in `tcp:5432`, `file:tmp/ready`, `command:pg_isready`
+// or `log:listening on`.
+#[derive(Debug, Clone)]
+pub enum Probe {
+    // Something accepts connections on host:port, or on a port of localhost.
+    Tcp(String),
+    // Relative to the directory of the process.
+    File(String),
+    // Exits 0 when run with sh in the directory and environment of the process.
+    Command(String),
+    // The process wrote a line that matches.
+    Log(Regex),
+}
+
+impl Probe {
+    // Log probes never pass here, they are matched against the output as it is read.
+    pub fn check(&self, cwd: &str, env: &HashMap<String, String>) -> bool {
+        match self {
+            Probe::Tcp(address) => accepts_connections(address),
+            Probe::File(path) => Path::new(cwd).join(path).exists(),
+            Probe::Command(command) => Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(cwd)
+                .envs(env)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false),
+            Probe::Log(_) => false,
+        }
+    }
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Probe, String> {
+        let (kind, value) = match s.split_once(':') {
+            Some((kind, value)) => (kind.trim(), value.trim()),
+            None => (s, ""),
+        };
+        if value.is_empty() && matches!(kind, "tcp" | "file" | "command" | "log") {
+            return Err(format!("{} probe needs a value", kind));
+        }
+        match kind {
+            "tcp" => {
+                let port = value.rsplit(':').next().unwrap_or(value);
+                match port.parse::<u16>() {
+                    Ok(_) => Ok(Probe::Tcp(value.to_string())),
+                    Err(_) => Err(format!("invalid port {:?} in tcp probe", port)),
+                }
+            }
+            "file" => Ok(Probe::File(value.to_string())),
+            "command" => Ok(Probe::Command(value.to_string())),
+            "log" => Regex::new(value)
+                .map(Probe::Log)
+                .map_err(|e| format!("invalid log pattern {:?}: {}", value, e)),
+            _ => Err(format!(
+                "invalid ready probe {:?}, expected tcp:, file:, command: or log:",
+                s
+            )),
+        }
+    }
+}
+
+fn accepts_connections(address: &str) -> bool {
+    let address = match address.contains(':') {
+        true => address.to_string(),
+        false => format!("127.0.0.1:{}", address),
+    };
+    match address.to_socket_addrs() {
+        Ok(mut addresses) => {
+            addresses.any(|i| TcpStream::connect_timeout(&i, Duration::from_millis(200)).is_ok())
+        }
+        Err(_) => false,
+    }
+}
+
+// The processes named in an `after: db, redis` annotation.
+pub fn parse_after(value: &str) -> Result<Vec<String>, String> {
+    let names: Vec<String> = value.split(',').map(|i| i.trim().to_string()).collect();
+    for name in names.iter() {
+        check_name(name).map_err(|e| format!("{} in after", e))?;
+    }
+    Ok(names)
+}
+
+// An order in which every process comes after the ones it depends on,
+// keeping the given order where it is free to. after[i] lists the processes
+// i depends on. Fails with a cycle, as in [a, b, a].
+pub fn start_order(after: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    let mut order = Vec::new();
+    let mut started = vec![false; after.len()];
+    while order.len() < after.len() {
+        let next = (0..after.len()).find(|i| !started[*i] && after[*i].iter().all(|j| started[*j]));
+        match next {
+            Some(i) => {
+                started[i] = true;
+                order.push(i);
+            }
+            None => return Err(find_cycle(after, &started)),
+        }
+    }
+    Ok(order)
+}
+
+// Every process left depends on another one that is left, so following
+// dependencies from any of them runs into a cycle.
+fn find_cycle(after: &[Vec<usize>], started: &[bool]) -> Vec<usize> {
+    let mut path = vec![(0..after.len()).find(|i| !started[*i]).unwrap()];
+    loop {
+        let last = *path.last().unwrap();
+        let next = *after[last].iter().find(|i| !started[**i]).unwrap();
+        if let Some(position) = path.iter().position(|i| *i == next) {
+            let mut cycle = path.split_off(position);
+            cycle.push(next);
+            return cycle;
+        }
+        path.push(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_probes() {
+        assert!(matches!("tcp:5432".parse(), Ok(Probe::Tcp(i)) if i == "5432"));
+        assert!(matches!("tcp: db:5432".parse(), Ok(Probe::Tcp(i)) if i == "db:5432"));
+        assert!(matches!("file:tmp/ready".parse(), Ok(Probe::File(i)) if i == "tmp/ready"));
+        assert!(
+            matches!("command:pg_isready -q".parse(), Ok(Probe::Command(i)) if i == "pg_isready -q")
+        );
+        assert!(matches!("log:listening on \\d+".parse(), Ok(Probe::Log(_))));
+        assert_eq!(
+            Err(String::from("invalid port \"x\" in tcp probe")),
+            "tcp:x".parse::<Probe>().map(|_| ())
+        );
+        assert_eq!(
+            Err(String::from("file probe needs a value")),
+            "file:".parse::<Probe>().map(|_| ())
+        );
+        assert_eq!(
+            Err(String::from(
+                "invalid ready probe \"http:80\", expected tcp:, file:, command: or log:"
+            )),
+            "http:80".parse::<Probe>().map(|_| ())
+        );
+        assert!("log:(".parse::<Probe>().is_err());
+    }
+
+    #[test]
+    fn test_checks() {
+        let env = HashMap::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(Probe::Tcp(port.to_string()).check(".", &env));
+        drop(listener);
+        assert!(!Probe::Tcp(port.to_string()).check(".", &env));
+        assert!(Probe::File("Procfile".to_string()).check("tests", &env));
+        assert!(!Probe::File("Procfile".to_string()).check(".", &env));
+        let mut env = HashMap::new();
+        env.insert("READY".to_string(), "yes".to_string());
+        assert!(Probe::Command("test \"$READY\" = yes".to_string()).check(".", &env));
+        assert!(!Probe::Command("false".to_string()).check(".", &env));
+    }
+
+    #[test]
+    fn test_parse_after() {
+        assert_eq!(
+            Ok(vec!["db".to_string(), "redis".to_string()]),
+            parse_after("db, redis")
+        );
+        assert_eq!(
+            Err(String::from("invalid process name \"\" in after")),
+            parse_after("db,")
+        );
+    }
+
+    #[test]
+    fn test_start_order() {
+        assert_eq!(Ok(vec![0, 1, 2]), start_order(&[vec![], vec![], vec![]]));
+        assert_eq!(Ok(vec![1, 2, 0]), start_order(&[vec![2], vec![], vec![1]]));
+        assert_eq!(Err(vec![0, 2, 0]), start_order(&[vec![2], vec![], vec![0]]));
+        assert_eq!(Err(vec![1, 1]), start_order(&[vec![], vec![1]]));
+    }
+}
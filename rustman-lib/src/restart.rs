@@ -0,0 +1,200 @@
+use crate::procfile::check_name;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::time::Duration;
+
+// What the engine does when an instance exits on its own. Never is the
+// Foreman behaviour of shutting everything down.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn restarts(&self, status: &ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RestartPolicy, String> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!(
+                "invalid restart policy {:?}, expected never, on-failure or always",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+// The policy of each process type, as in `always` or `all=on-failure,web=always`.
+// A policy for a named process wins over the `all` one.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Restarts {
+    default: Option<RestartPolicy>,
+    policies: BTreeMap<String, RestartPolicy>,
+}
+
+impl Restarts {
+    pub fn policy(&self, name: &str) -> RestartPolicy {
+        self.policies
+            .get(name)
+            .copied()
+            .or(self.default)
+            .unwrap_or_default()
+    }
+
+    // Used for Procfile annotations, which give way to a policy named for
+    // the process but not to the `all` one.
+    pub fn set_unless_named(&mut self, name: &str, policy: RestartPolicy) {
+        self.policies.entry(name.to_string()).or_insert(policy);
+    }
+}
+
+impl FromStr for Restarts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Restarts, String> {
+        let mut restarts = Restarts::default();
+        let restarts_string: String = s.chars().filter(|i| !i.is_whitespace()).collect();
+        for pair in restarts_string.split(',') {
+            let (process, policy) = match pair.split_once('=') {
+                Some((process, policy)) => (process, policy),
+                None => ("all", pair),
+            };
+            check_name(process)?;
+            let policy = policy.parse()?;
+            if process == "all" {
+                restarts.default = Some(policy);
+            } else {
+                restarts.policies.insert(process.to_string(), policy);
+            }
+        }
+        Ok(restarts)
+    }
+}
+
+// How soon and how often an instance is restarted. The delay doubles with
+// every restart up to max_delay, an instance that stayed up for reset_after
+// starts counting from the first restart again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub delay: Duration,
+    pub max_delay: Duration,
+    pub max_restarts: u32,
+    pub reset_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_restarts: 5,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    // The number of the next restart of an instance that was restarted
+    // `restarts` times in a row and then ran for `uptime`, or None once the
+    // restarts are used up.
+    pub fn next_restart(&self, restarts: u32, uptime: Duration) -> Option<u32> {
+        let restart = match uptime >= self.reset_after {
+            true => 1,
+            false => restarts + 1,
+        };
+        match restart <= self.max_restarts {
+            true => Some(restart),
+            false => None,
+        }
+    }
+
+    // Counting from 1.
+    pub fn delay_for(&self, restart: u32) -> Duration {
+        let factor = 2u32.saturating_pow(restart.saturating_sub(1));
+        self.delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_policies() {
+        let success = ExitStatus::from_raw(0);
+        let failure = ExitStatus::from_raw(1 << 8);
+        assert!(!RestartPolicy::Never.restarts(&failure));
+        assert!(!RestartPolicy::OnFailure.restarts(&success));
+        assert!(RestartPolicy::OnFailure.restarts(&failure));
+        assert!(RestartPolicy::Always.restarts(&success));
+        assert_eq!(Ok(RestartPolicy::OnFailure), "on-failure".parse());
+        assert_eq!(
+            Err(String::from(
+                "invalid restart policy \"sometimes\", expected never, on-failure or always"
+            )),
+            "sometimes".parse::<RestartPolicy>()
+        );
+    }
+
+    #[test]
+    fn test_parse_restarts() {
+        let restarts: Restarts = "always".parse().unwrap();
+        assert_eq!(RestartPolicy::Always, restarts.policy("web"));
+        let restarts: Restarts = "all=on-failure, web = always".parse().unwrap();
+        assert_eq!(RestartPolicy::Always, restarts.policy("web"));
+        assert_eq!(RestartPolicy::OnFailure, restarts.policy("worker"));
+        let restarts: Restarts = "web=always".parse().unwrap();
+        assert_eq!(RestartPolicy::Never, restarts.policy("worker"));
+        assert!("we.b=always".parse::<Restarts>().is_err());
+        assert!("web=often".parse::<Restarts>().is_err());
+    }
+
+    #[test]
+    fn test_annotations_give_way_to_named_policies() {
+        let mut restarts: Restarts = "all=never,web=on-failure".parse().unwrap();
+        restarts.set_unless_named("web", RestartPolicy::Always);
+        restarts.set_unless_named("worker", RestartPolicy::Always);
+        assert_eq!(RestartPolicy::OnFailure, restarts.policy("web"));
+        assert_eq!(RestartPolicy::Always, restarts.policy("worker"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::default();
+        let delays: Vec<u64> = (1..=7).map(|i| backoff.delay_for(i).as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 30, 30], delays);
+        assert_eq!(30, backoff.delay_for(100).as_secs());
+        assert_eq!(Some(1), backoff.next_restart(0, Duration::from_secs(1)));
+        assert_eq!(Some(5), backoff.next_restart(4, Duration::from_secs(1)));
+        assert_eq!(None, backoff.next_restart(5, Duration::from_secs(1)));
+        assert_eq!(Some(1), backoff.next_restart(5, Duration::from_secs(60)));
+    }
+}
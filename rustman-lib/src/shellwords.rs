@@ -0,0 +1,250 @@
+use crate::expand::ExpandError;
+use std::error::Error;
+use std::fmt;
+
+// Characters that only mean something to a shell. They have to be quoted
+// when a command is run without one.
+const OPERATORS: &str = "|&;<>()`";
+
+#[derive(Debug, PartialEq)]
+pub enum ShellwordsError {
+    // Columns are 1-based.
+    UnmatchedQuote(usize),
+    Operator(char, usize),
+    Expand(ExpandError),
+}
+
+impl fmt::Display for ShellwordsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellwordsError::UnmatchedQuote(column) => {
+                write!(f, "unmatched quote at column {}", column)
+            }
+            ShellwordsError::Operator(c, column) => write!(
+                f,
+                "{:?} at column {} needs a shell, quote it or drop the exec: prefix",
+                c, column
+            ),
+            ShellwordsError::Expand(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ShellwordsError {}
+
+// Splits a command line into words the way sh would, without expanding
+// anything.
+pub fn split(input: &str) -> Result<Vec<String>, ShellwordsError> {
+    split_with(input, |text| Ok(text.to_string()))
+}
+
+// Splits a command line into words the way sh would. Unquoted and double
+// quoted text goes through expand, single quoted and escaped text does not.
+// Unlike sh, an expanded value is never split into more words.
+pub fn split_with<F>(input: &str, expand: F) -> Result<Vec<String>, ShellwordsError>
+where
+    F: FnMut(&str) -> Result<String, ExpandError>,
+{
+    let mut splitter = Splitter {
+        expand,
+        words: Vec::new(),
+        word: None,
+        raw: String::new(),
+    };
+    splitter.split(input)?;
+    Ok(splitter.words)
+}
+
+struct Splitter<F> {
+    expand: F,
+    words: Vec<String>,
+    // None between words, so that '' still gives an empty word.
+    word: Option<String>,
+    // Text waiting to be expanded
+    raw: String,
+}
+
+impl<F> Splitter<F>
+where
+    F: FnMut(&str) -> Result<String, ExpandError>,
+{
+    fn split(&mut self, input: &str) -> Result<(), ShellwordsError> {
+        let chars: Vec<(usize, char)> = input.char_indices().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let (position, c) = chars[i];
+            let next = chars.get(i + 1).map(|(_, c)| *c);
+            match c {
+                _ if c.is_whitespace() => {
+                    self.end_word()?;
+                    i += 1;
+                }
+                '\'' => {
+                    let end = (i + 1..chars.len())
+                        .find(|j| chars[*j].1 == '\'')
+                        .ok_or(ShellwordsError::UnmatchedQuote(position + 1))?;
+                    let text: String = chars[i + 1..end].iter().map(|(_, c)| c).collect();
+                    self.literal(&text)?;
+                    i = end + 1;
+                }
+                '"' => i = self.double_quoted(&chars, i)?,
+                '\\' => {
+                    match next {
+                        // A line continuation
+                        Some('\n') => {}
+                        Some(c) => self.literal(&c.to_string())?,
+                        None => self.literal("\\")?,
+                    }
+                    i += 2;
+                }
+                '$' if next == Some('{') => i = self.braces(&chars, i),
+                _ if OPERATORS.contains(c) => {
+                    return Err(ShellwordsError::Operator(c, position + 1));
+                }
+                _ => {
+                    self.raw.push(c);
+                    i += 1;
+                }
+            }
+        }
+        self.end_word()
+    }
+
+    // Returns the index just past the closing quote.
+    fn double_quoted(
+        &mut self,
+        chars: &[(usize, char)],
+        start: usize,
+    ) -> Result<usize, ShellwordsError> {
+        self.word.get_or_insert_with(String::new);
+        let mut i = start + 1;
+        loop {
+            let c = match chars.get(i) {
+                Some((_, c)) => *c,
+                None => return Err(ShellwordsError::UnmatchedQuote(chars[start].0 + 1)),
+            };
+            let next = chars.get(i + 1).map(|(_, c)| *c);
+            match c {
+                '"' => return Ok(i + 1),
+                // Inside double quotes a backslash only escapes these
+                '\\' if matches!(next, Some(c) if "$`\"\\\n".contains(c)) => {
+                    if next != Some('\n') {
+                        self.literal(&next.unwrap().to_string())?;
+                    }
+                    i += 2;
+                }
+                '$' if next == Some('{') => i = self.braces(chars, i),
+                _ => {
+                    self.raw.push(c);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // Keeps ${...} in one piece, even when a default has spaces or quotes in
+    // it. An unterminated one is left for expand to report.
+    fn braces(&mut self, chars: &[(usize, char)], start: usize) -> usize {
+        let mut depth = 0;
+        for (i, (_, c)) in chars.iter().enumerate().skip(start + 2) {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    self.raw.extend(chars[start..=i].iter().map(|(_, c)| c));
+                    return i + 1;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        self.raw.extend(chars[start..].iter().map(|(_, c)| c));
+        chars.len()
+    }
+
+    fn literal(&mut self, text: &str) -> Result<(), ShellwordsError> {
+        self.flush()?;
+        self.word.get_or_insert_with(String::new).push_str(text);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ShellwordsError> {
+        if !self.raw.is_empty() {
+            let text = (self.expand)(&self.raw).map_err(ShellwordsError::Expand)?;
+            self.word.get_or_insert_with(String::new).push_str(&text);
+            self.raw.clear();
+        }
+        Ok(())
+    }
+
+    fn end_word(&mut self) -> Result<(), ShellwordsError> {
+        self.flush()?;
+        if let Some(word) = self.word.take() {
+            self.words.push(word);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::expand;
+
+    fn words(input: &str) -> Vec<String> {
+        split(input).unwrap()
+    }
+
+    fn expanded(input: &str) -> Result<Vec<String>, ShellwordsError> {
+        let lookup = |name: &str| match name {
+            "FOO" => Some(String::from("foo bar")),
+            _ => None,
+        };
+        split_with(input, |text| expand(text, lookup, true))
+    }
+
+    #[test]
+    fn test_splits_on_whitespace() {
+        assert_eq!(
+            vec!["bin/web", "-p", "5000"],
+            words("  bin/web\t-p   5000 ")
+        );
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(vec!["a b", "c d", ""], words("'a b' \"c d\" ''"));
+        assert_eq!(vec!["it's", "a\"b"], words("it\\'s 'a\"b'"));
+        assert_eq!(vec!["abc"], words("a'b'\"c\""));
+        assert_eq!(vec!["a\\b", "$x", "\\n"], words("'a\\b' \"\\$x\" \"\\n\""));
+        assert_eq!(vec!["a b", "ab"], words("a\\ b a\\\nb"));
+        assert_eq!(vec!["a|b", "c;d"], words("'a|b' c\\;d"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(ShellwordsError::UnmatchedQuote(6)), split("echo 'hi"));
+        assert_eq!(Err(ShellwordsError::UnmatchedQuote(3)), split("a \"b"));
+        let error = split("cat log | grep x").unwrap_err();
+        assert_eq!(ShellwordsError::Operator('|', 9), error);
+        assert_eq!(
+            "'|' at column 9 needs a shell, quote it or drop the exec: prefix",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_expansion() {
+        let expected: Vec<String> = vec!["foo bar", "$FOO", "x-foo bar", "a b"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(Ok(expected), expanded("$FOO '$FOO' x-\"$FOO\" ${BAR:-a b}"));
+        assert_eq!(
+            Err(ShellwordsError::Expand(ExpandError::Undefined(
+                "BAR".to_string()
+            ))),
+            expanded("$BAR")
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use rand::Rng;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+// A file for tests, removed again when dropped.
+pub struct TmpFile {
+    pub filename: String,
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        TmpFile::delete_file(self.filename.as_str());
+    }
+}
+
+impl TmpFile {
+    pub fn delete_file(filename: &str) {
+        if Path::new(filename).exists() {
+            std::fs::remove_file(filename).expect("delete_file failed removing file");
+        }
+    }
+
+    // Writes to `prefix` with a random number appended, so tests running
+    // at the same time do not share files.
+    pub fn write(prefix: &str, contents: &str) -> TmpFile {
+        let random_number = rand::thread_rng().gen_range(0, 1000000);
+        let filename = format!("{}.{}", prefix, random_number);
+        let mut file = File::create(filename.as_str()).expect("write failed creating file");
+        file.write_all(contents.as_bytes())
+            .expect("write failed writing file");
+        file.sync_all().expect("write failed syncing file");
+        TmpFile { filename }
+    }
+}
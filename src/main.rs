@@ -1,44 +1,311 @@
-// TODO
-// https://rust-cli.github.io/book/index.html
-
 extern crate rustman_lib;
-//use rustman_lib::process::Process;
-//use rustman_lib::procfile::Procfile;
-//use std::collections::HashMap;
-use std::process::Command;
-use std::str;
+use clap::{Parser, Subcommand};
+use rustman_lib::engine::{Engine, EngineOptions};
+use rustman_lib::formation::Formation;
+use rustman_lib::procfile::{Procfile, ProcfileError, Severity};
+use rustman_lib::restart::{Backoff, Restarts};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{self, Command, ExitStatus};
+use std::time::Duration;
 
-//fn print(count: &mut i32, hash: &HashMap<String, String>, name: &str) {
-//    *count += 1;
-//    print!("{}: {} = {:#?}\n", name, count, hash);
-//}
+#[derive(Parser, Debug)]
+#[command(name = "rustman", version, about = "Foreman in Rust")]
+struct Opt {
+    /// Specify an alternate Procfile to load, implies -d at the Procfile root
+    #[arg(short = 'f', long, global = true)]
+    procfile: Option<String>,
+    /// Specify one or more .env files to load, separated by commas
+    #[arg(short = 'e', long, global = true)]
+    env: Option<String>,
+    /// Specify an alternate application root. This defaults to the directory containing the Procfile
+    #[arg(short = 'd', long, global = true)]
+    root: Option<String>,
+    /// Specify what processes will run and how many. Default: all=1
+    #[arg(short = 'm', long, global = true)]
+    formation: Option<Formation>,
+    /// Specify which port to use as the base for this application. Default: 5000
+    #[arg(short = 'p', long, global = true)]
+    port: Option<u32>,
+    /// Specify the amount of time (in seconds) processes have to shutdown gracefully before receiving a SIGKILL
+    #[arg(short = 't', long, global = true, default_value_t = 5)]
+    timeout: u64,
+    /// Refuse to start processes whose commands use undefined variables
+    #[arg(long, global = true)]
+    strict_env: bool,
+    /// Restart processes that exit: never, on-failure or always, for all processes or per process as in web=always,worker=on-failure. Default: never
+    #[arg(long, global = true)]
+    restart: Option<Restarts>,
+    /// Specify how many times in a row a process is restarted before rustman gives up and shuts down
+    #[arg(long, global = true, default_value_t = 5)]
+    max_restarts: u32,
+    /// Specify how long (in seconds) a restarted process has to stay up before its restarts are counted from zero again
+    #[arg(long, global = true, default_value_t = 60)]
+    restart_window: u64,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
 
-fn main() {
-    //let mut count = 0;
-    //let mut opt: HashMap<String, String> = HashMap::new();
-    //let mut env: HashMap<String, String> = HashMap::new();
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Start the application (or a specific PROCESS)
+    Start { process: Option<String> },
+    /// Run a command using your application's environment
+    Run {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Validate your application's Procfile
+    Check,
+    /// Edit your application's Procfile
+    Procfile {
+        #[command(subcommand)]
+        command: ProcfileCommands,
+    },
+    /// Display rustman version
+    Version,
+}
 
-    let cmd = "rustman-lib/tests/test.sh";
+#[derive(Subcommand, Debug)]
+enum ProcfileCommands {
+    /// Add a process, or replace the command of an existing one
+    Add {
+        name: String,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Remove a process
+    Rm { name: String },
+}
 
-    //let output = Command::new(cmd).
-    //    output().
-    //    expect("failed to execute process");
-    //print!("output = {:#?}", output);
+fn main() {
+    let opt = Opt::parse();
+    match &opt.command {
+        None => start(&opt, None),
+        Some(Commands::Start { process }) => start(&opt, process.as_deref()),
+        Some(Commands::Run { command }) => run(&opt, command),
+        Some(Commands::Check) => check(&opt),
+        Some(Commands::Procfile { command }) => edit_procfile(&opt, command),
+        Some(Commands::Version) => println!("rustman {}", env!("CARGO_PKG_VERSION")),
+    }
+}
 
-    //let output = Command::new("sh").
-    //    arg("-c").
-    //    arg(&cmd).
-    //    spawn().
-    //    expect("failed to execute process");
-    //print!("output = {:#?}", output);
+fn start(opt: &Opt, process: Option<&str>) {
+    let mut engine = engine(opt);
+    if let Some(process) = process {
+        if engine.process(process).is_none() {
+            error(&format!("no such process: {}", process));
+        }
+        engine.options_mut().formation = format!("{}=1", process).parse().unwrap();
+    }
+    let status = match engine.start() {
+        Ok(status) => status,
+        Err(e) => error(&e.to_string()),
+    };
+    if let Some(status) = status {
+        process::exit(exit_code(status));
+    }
+}
 
-    let output = Command::new("sh")
+// Runs without a Procfile too, with the environment of the .env files only.
+fn run(opt: &Opt, args: &[String]) {
+    let mut engine = new_engine(opt);
+    let procfile = procfile_path(opt);
+    if Path::new(&procfile).is_file() {
+        load_procfile(&mut engine, &procfile);
+    }
+    load_environment(opt, &mut engine);
+    // A single argument naming a Procfile entry runs that entry, any other
+    // single argument is handed to the shell as is.
+    let command = match args {
+        [arg] => match engine.process(arg) {
+            Some(process) => match process.direct_command() {
+                Some(command) => format!("exec {}", command),
+                None => process.command().to_string(),
+            },
+            None => arg.to_string(),
+        },
+        _ => shell_join(args),
+    };
+    let status = Command::new("sh")
         .arg("-c")
-        .arg(&cmd)
-        .output()
-        .expect("failed to execute process");
+        .arg(&command)
+        .envs(engine.env())
+        .status();
+    match status {
+        Ok(status) => process::exit(exit_code(status)),
+        Err(e) => error(&format!("could not run {}: {}", command, e)),
+    }
+}
+
+fn check(opt: &Opt) {
+    let procfile = procfile(opt);
+    let diagnostics = match Procfile::validate(&procfile) {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => error(&e.to_string()),
+    };
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}: {}", procfile, diagnostic);
+    }
+    if diagnostics.iter().any(|i| i.severity == Severity::Error) {
+        error(&format!("{} is not a valid Procfile", procfile));
+    }
+    let engine = engine(opt);
+    let names = engine.process_names();
+    if names.is_empty() {
+        error("no processes defined");
+    }
+    println!("valid procfile detected ({})", names.join(", "));
+}
+
+// Adding to a Procfile that does not exist yet creates it.
+fn edit_procfile(opt: &Opt, command: &ProcfileCommands) {
+    let filename = procfile_path(opt);
+    let mut procfile = match Path::new(&filename).exists() {
+        true => Procfile::new(Some(&filename)),
+        false => Procfile::new(None),
+    }
+    .unwrap_or_else(|e| match e {
+        ProcfileError::Io { .. } => error(&e.to_string()),
+        _ => error(&format!("{}: {}", filename, e)),
+    });
+    match command {
+        ProcfileCommands::Add { name, command } => {
+            if let Err(e) = procfile.set(name, &shell_join(command)) {
+                error(&e.to_string());
+            }
+        }
+        ProcfileCommands::Rm { name } => {
+            if procfile.remove(name).is_none() {
+                error(&format!("no such process: {}", name));
+            }
+        }
+    }
+    if let Err(e) = procfile.save(Some(&filename)) {
+        error(&e.to_string());
+    }
+}
+
+fn procfile_path(opt: &Opt) -> String {
+    let root = opt.root.clone().unwrap_or_else(|| String::from("."));
+    match &opt.procfile {
+        Some(i) => i.clone(),
+        None => Path::new(&root)
+            .join("Procfile")
+            .to_string_lossy()
+            .to_string(),
+    }
+}
+
+fn procfile(opt: &Opt) -> String {
+    let procfile = procfile_path(opt);
+    if !Path::new(&procfile).is_file() {
+        error(&format!("{} does not exist.", procfile));
+    }
+    procfile
+}
+
+fn engine(opt: &Opt) -> Engine {
+    let procfile = procfile(opt);
+    let mut engine = new_engine(opt);
+    load_procfile(&mut engine, &procfile);
+    load_environment(opt, &mut engine);
+    engine
+}
+
+fn new_engine(opt: &Opt) -> Engine {
+    Engine::new(EngineOptions {
+        formation: opt.formation.clone().unwrap_or_default(),
+        port: opt.port,
+        root: opt.root.clone(),
+        timeout: opt.timeout,
+        strict_env: opt.strict_env,
+        restart: opt.restart.clone().unwrap_or_default(),
+        backoff: Backoff {
+            max_restarts: opt.max_restarts,
+            reset_after: Duration::from_secs(opt.restart_window),
+            ..Backoff::default()
+        },
+    })
+}
+
+fn load_procfile(engine: &mut Engine, procfile: &str) {
+    match engine.load_procfile(procfile) {
+        Err(e @ ProcfileError::Io { .. }) => error(&e.to_string()),
+        Err(e) => error(&format!("{}: {}", procfile, e)),
+        Ok(_) => {}
+    }
+}
+
+// Named env files must exist, the default .env is only loaded when present.
+fn load_environment(opt: &Opt, engine: &mut Engine) {
+    match &opt.env {
+        Some(files) => {
+            for file in files.split(',') {
+                if let Err(e) = engine.load_env(file) {
+                    error(&format!("{}: {}", file, e));
+                }
+            }
+        }
+        None => {
+            let default_env = Path::new(&engine.root()).join(".env");
+            if default_env.exists() {
+                if let Err(e) = engine.load_env(&default_env.to_string_lossy()) {
+                    error(&format!("{}: {}", default_env.display(), e));
+                }
+            }
+        }
+    }
+}
+
+// Mirror the shell convention of 128 + signal number for signaled children
+fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+// A single argument is taken as a command line already, several are quoted
+// so the shell sees the same words.
+fn shell_join(args: &[String]) -> String {
+    match args {
+        [arg] => arg.to_string(),
+        _ => args
+            .iter()
+            .map(|arg| shell_escape(arg))
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+fn shell_escape(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn error(message: &str) -> ! {
+    eprintln!("ERROR: {}", message);
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustman_lib::shellwords;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|i| i.to_string()).collect()
+    }
 
-    print!("output = {}", str::from_utf8(&output.stdout).unwrap());
-    //let procfile = Procfile::new(String::from("rustman-lib/tests/Procfile1.test"));
-    //println!("procfile:\n{}", procfile);
+    #[test]
+    fn test_shell_join_keeps_the_words() {
+        let words = args(&["printf", "%s|", "a  b", "c;d", "it's", ""]);
+        assert_eq!(words, shellwords::split(&shell_join(&words)).unwrap());
+        assert_eq!("bin/web -p $PORT", shell_join(&args(&["bin/web -p $PORT"])));
+    }
 }